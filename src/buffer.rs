@@ -0,0 +1,220 @@
+//! Aligned buffers used to move blocks between the reader and writer.
+//!
+//! Buffers are taken from a [Pool] by the reader and returned by the writer once written,
+//! so the number of buffers, and the memory used, is bounded by the `--buffer-size` budget.
+
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+/// Returns the alignment of every [Buffer].
+///
+/// Buffers are page aligned, which satisfies the memory alignment required for `O_DIRECT` by
+/// any device with a logical block size up to the page size.
+pub fn alignment() -> usize {
+    static PAGE: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
+    // SAFETY: sysconf has no preconditions
+    *PAGE.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        p if p > 0 => p as usize,
+        _ => 4096,
+    })
+}
+
+/// A fixed capacity, page aligned byte buffer.
+///
+/// The length may be reduced with [Buffer::truncate] or grown up to the capacity
+/// with [Buffer::extend_from_slice]. The whole capacity is always initialized.
+pub struct Buffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    cap: usize,
+}
+
+// SAFETY: Buffer owns its allocation like a Box<[u8]>
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    /// Allocates a zeroed buffer of `cap` bytes with the length set to `cap`.
+    pub fn new(cap: usize) -> Self {
+        let ptr = if cap == 0 {
+            // a dangling pointer must still be aligned
+            NonNull::new(alignment() as *mut u8).unwrap()
+        } else {
+            // SAFETY: layout has a non zero size
+            let p = unsafe { std::alloc::alloc_zeroed(Self::layout(cap)) };
+            NonNull::new(p).unwrap_or_else(|| std::alloc::handle_alloc_error(Self::layout(cap)))
+        };
+
+        Self {
+            ptr,
+            len: cap,
+            cap,
+        }
+    }
+
+    /// Allocates a buffer of `cap` bytes with a length of 0.
+    pub fn empty(cap: usize) -> Self {
+        let mut b = Self::new(cap);
+        b.len = 0;
+        b
+    }
+
+    fn layout(cap: usize) -> Layout {
+        Layout::from_size_align(cap, alignment()).expect("Buffer too large")
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Shortens the buffer to `len`, does nothing if `len` is greater than the current length.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Sets the length of the buffer, the contents are whatever was last written there.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `len` is greater than the capacity of the buffer.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.cap, "Buffer overflow");
+        self.len = len;
+    }
+
+    /// Appends `data` to the buffer.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `data` does not fit within the capacity of the buffer.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.cap - self.len, "Buffer overflow");
+        // SAFETY: checked that the range is within the allocation, data cannot alias a &mut self
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.as_ptr().add(self.len), data.len()) };
+        self.len += data.len();
+    }
+}
+
+impl std::ops::Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the whole capacity is initialized and len <= cap
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl std::ops::DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: the whole capacity is initialized and len <= cap
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.cap > 0 {
+            // SAFETY: ptr was allocated with this layout in Self::new
+            unsafe { std::alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.cap)) }
+        }
+    }
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Buffer").field("len", &self.len).field("cap", &self.cap).finish()
+    }
+}
+
+/// Buffers returned by the writer, shared by a [Pool] and its [Recycler].
+struct Free {
+    buffers: std::sync::Mutex<FreeList>,
+    returned: std::sync::Condvar,
+}
+
+struct FreeList {
+    buffers: Vec<Buffer>,
+    /// Set once the [Recycler] is dropped and no more buffers will be returned
+    closed: bool,
+}
+
+impl Free {
+    fn lock(&self) -> std::sync::MutexGuard<'_, FreeList> {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A bounded set of recycled buffers.
+pub struct Pool {
+    free: std::sync::Arc<Free>,
+    allocated: usize,
+    max: usize,
+    cap: usize,
+}
+
+/// Returns buffers to a [Pool] once they have been written.
+pub struct Recycler(std::sync::Arc<Free>);
+
+impl Recycler {
+    pub fn put(&self, b: Buffer) {
+        self.0.lock().buffers.push(b);
+        self.0.returned.notify_one();
+    }
+}
+
+impl Drop for Recycler {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.returned.notify_all();
+    }
+}
+
+impl Pool {
+    /// Creates a pool of buffers of `cap` bytes using up to `budget` bytes, but never less than
+    /// two buffers. Buffers are returned to the pool through the [Recycler].
+    pub fn new(budget: usize, cap: usize) -> (Self, Recycler) {
+        let free = std::sync::Arc::new(Free {
+            buffers: std::sync::Mutex::new(FreeList { buffers: Vec::new(), closed: false }),
+            returned: std::sync::Condvar::new(),
+        });
+        // taking the lock keeps the wake up from landing between a check and the wait in get
+        let wake = free.clone();
+        crate::signal::on_stop(move || {
+            let _free = wake.lock();
+            wake.returned.notify_all();
+        });
+        let pool = Self {
+            free: free.clone(),
+            allocated: 0,
+            max: (budget / cap.max(1)).max(2),
+            cap,
+        };
+        (pool, Recycler(free))
+    }
+
+    /// The maximum number of buffers which will be allocated.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Returns an empty buffer, waiting for one to be returned if the budget is used up.
+    ///
+    /// Once the transfer is being stopped by a signal, or the writer has gone away,
+    /// buffers are allocated past the budget so the reader does not wait forever.
+    pub fn get(&mut self) -> Buffer {
+        let mut free = self.free.lock();
+        let mut b = loop {
+            if let Some(b) = free.buffers.pop() {
+                break b;
+            }
+            if self.allocated < self.max || free.closed || crate::signal::pending().is_some() {
+                self.allocated += 1;
+                break Buffer::empty(self.cap);
+            }
+            free = self.free.returned.wait(free).unwrap_or_else(|e| e.into_inner());
+        };
+        drop(free);
+        b.set_len(0);
+        b
+    }
+}
@@ -0,0 +1,282 @@
+//! Resumable transfers for `--checkpoint` and `--resume`.
+//!
+//! Every [INTERVAL] the writer flushes the output and records how far the input and output
+//! got in a small `key=value` file. The file is only written once the output is durable, so a
+//! later run with `--resume` can continue from it after checking the input is still the same.
+//! This relies on every input byte becoming one output byte written in order, so conversions
+//! which depend on the order of the input, appending and `--jobs` can not be used with it.
+
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// How often the output is flushed and the checkpoint updated
+const INTERVAL: Duration = Duration::from_secs(10);
+/// Bytes at the start of the input hashed into the fingerprint
+const FINGERPRINT_LEN: usize = 1024 * 1024;
+
+/// A point up to which the output is known to be durable, as stored in the checkpoint file.
+#[derive(Debug, Clone)]
+pub struct Point {
+    input: String,
+    output: String,
+    ibs: usize,
+    obs: usize,
+    input_offset: u64,
+    output_offset: u64,
+    /// Bytes left to copy if `count=` was given
+    count_bytes: Option<u64>,
+    fingerprint: String,
+}
+
+impl Point {
+    /// Reads the checkpoint at `path`, exiting with 0x40 if it can not be read.
+    pub fn load(path: &Path) -> Self {
+        let src = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Unable to read checkpoint {}: {e}", path.display());
+            std::process::exit(0x40);
+        });
+        let mut fields = std::collections::HashMap::new();
+        for line in src.lines().filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            if let Some((k, v)) = line.split_once('=') {
+                fields.insert(k, v);
+            }
+        }
+
+        let field = |key: &str| fields.get(key).copied().unwrap_or_else(|| {
+            eprintln!("Checkpoint {} has no '{key}'", path.display());
+            std::process::exit(0x40);
+        });
+        let number = |key: &str| field(key).parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Checkpoint {} has an invalid '{key}'", path.display());
+            std::process::exit(0x40);
+        });
+        Self {
+            input: field("input").to_string(),
+            output: field("output").to_string(),
+            ibs: number("ibs") as usize,
+            obs: number("obs") as usize,
+            input_offset: number("input_offset"),
+            output_offset: number("output_offset"),
+            count_bytes: fields.contains_key("count_bytes").then(|| number("count_bytes")),
+            fingerprint: field("fingerprint").to_string(),
+        }
+    }
+
+    /// Replaces the checkpoint at `path`, through a temporary file so it is never left half written.
+    fn save(&self, path: &Path) {
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let write = || -> std::io::Result<()> {
+            let f = std::fs::File::create(&tmp)?;
+            f.write_all_at(self.to_string().as_bytes(), 0)?;
+            f.sync_all()?;
+            std::fs::rename(&tmp, path)
+        };
+        write().unwrap_or_else(|e| crate::handle_err(e, &format!("checkpoint {}", path.display()), 0x42));
+    }
+}
+
+impl std::fmt::Display for Point {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# disk-destroyer checkpoint, continue the transfer with --resume")?;
+        writeln!(f, "input={}", self.input)?;
+        writeln!(f, "output={}", self.output)?;
+        writeln!(f, "ibs={}", self.ibs)?;
+        writeln!(f, "obs={}", self.obs)?;
+        writeln!(f, "input_offset={}", self.input_offset)?;
+        writeln!(f, "output_offset={}", self.output_offset)?;
+        if let Some(c) = self.count_bytes {
+            writeln!(f, "count_bytes={c}")?;
+        }
+        writeln!(f, "fingerprint={}", self.fingerprint)
+    }
+}
+
+/// Continues from `point` by replacing `skip=`, `seek=` and `count=` with what was left.
+/// Exits with 0x41 if the block sizes differ from those of the interrupted transfer.
+pub fn resume(opts: &mut crate::Options, point: &Point) {
+    if (point.ibs, point.obs) != (opts.i_bs, opts.o_bs) {
+        eprintln!("The checkpoint was written with ibs={} obs={}, not ibs={} obs={}", point.ibs, point.obs, opts.i_bs, opts.o_bs);
+        std::process::exit(0x41);
+    }
+    opts.i_skip = Some(point.input_offset as usize);
+    opts.i_flag.skip_bytes = true;
+    opts.o_skip = Some(point.output_offset as usize);
+    opts.o_flag.seek_bytes = true;
+    if let Some(c) = point.count_bytes {
+        opts.count = Some(c as usize);
+        opts.i_flag.count_bytes = true;
+    }
+}
+
+/// Identifies the input by its size and a hash of its start.
+fn fingerprint(opts: &crate::Options, input: &std::fs::File) -> String {
+    let size = crate::io::size(input).unwrap_or(0);
+    // aligned in case the input uses O_DIRECT
+    let mut buff = crate::buffer::Buffer::new(FINGERPRINT_LEN);
+    let len = crate::io::read_at(input, &mut buff, 0)
+        .unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.i_f), 0x21));
+    format!("{size}:{:016x}", crate::io::fnv1a(crate::io::FNV_OFFSET, &buff[..len]))
+}
+
+/// Returns why the transfer can not be checkpointed, if it can not.
+fn refusal(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> Option<&'static str> {
+    if opts.resume.is_some() && (opts.verify || !opts.hash.is_empty()) {
+        Some("--hash and --verify would only cover the part copied after resuming")
+    } else if crate::conv::Converter::new(&opts.conv, opts.cbs).is_ordered() {
+        Some("conversions which depend on the order of the input are active")
+    } else if opts.o_flag.append {
+        Some("the output is opened for appending")
+    } else if !crate::io::seekable(input) || !crate::io::seekable(output) {
+        Some("the input and output must both be seekable")
+    } else {
+        None
+    }
+}
+
+/// Periodically records how far the transfer got.
+pub struct Checkpoint {
+    path: PathBuf,
+    point: Point,
+    /// Offsets at which this run started
+    i_base: u64,
+    o_base: u64,
+    last: Instant,
+}
+
+impl Checkpoint {
+    /// Starts checkpointing if `--checkpoint` or `--resume` was given.
+    /// Exits with 0x43 if the transfer can not be checkpointed and with 0x41 if the input is not
+    /// the one being resumed.
+    pub fn start(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> Option<Self> {
+        let path = opts.checkpoint.clone()?;
+        if let Some(why) = refusal(opts, input, output) {
+            eprintln!("Can not checkpoint to {}, {why}", path.display());
+            crate::exit_on_err(0x43)
+        }
+
+        let fingerprint = fingerprint(opts, input);
+        if let Some(p) = &opts.resume {
+            if p.fingerprint != fingerprint {
+                eprintln!("{} is not the input the checkpoint was written for, it was {}", opts.i_f, p.input);
+                crate::exit_on_err(0x41)
+            }
+            info!("Resuming at input offset {} and output offset {}", p.input_offset, p.output_offset);
+        }
+
+        let position = |mut f: &std::fs::File| std::io::Seek::stream_position(&mut f).unwrap_or(0);
+        Some(Self {
+            point: Point {
+                input: opts.i_f.to_string(),
+                output: opts.o_f.to_string(),
+                ibs: opts.i_bs,
+                obs: opts.o_bs,
+                input_offset: 0,
+                output_offset: 0,
+                count_bytes: opts.count_bytes(),
+                fingerprint,
+            },
+            i_base: position(input),
+            o_base: position(output),
+            path,
+            last: Instant::now(),
+        })
+    }
+
+    /// Flushes `output` and records everything written so far.
+    fn save(&mut self, output: &std::fs::File) {
+        let busy = crate::watchdog::busy(crate::watchdog::Side::Flush);
+        output.sync_data().unwrap_or_else(|e| crate::handle_err(e, &format!("syncing {}", self.point.output), 0x22));
+        drop(busy);
+        let done = crate::STATE.bytes_written.load(Ordering::Relaxed) as u64;
+        let point = Point {
+            input_offset: self.i_base + done,
+            output_offset: self.o_base + done,
+            count_bytes: self.point.count_bytes.map(|c| c.saturating_sub(done)),
+            ..self.point.clone()
+        };
+        point.save(&self.path);
+        self.last = Instant::now();
+    }
+
+    /// Saves the checkpoint if it is due, called after every block written to `output`.
+    pub fn update(&mut self, output: &std::fs::File) {
+        if self.last.elapsed() >= INTERVAL {
+            self.save(output);
+        }
+    }
+
+    /// Saves the final checkpoint if the transfer was interrupted, otherwise the checkpoint is
+    /// removed as there is nothing left to resume.
+    pub fn finish(mut self, output: &std::fs::File) {
+        if crate::signal::pending().is_some() {
+            self.save(output);
+            info!("Saved checkpoint to {0}, continue with --resume {0}", self.path.display());
+        } else if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                info!("Unable to remove checkpoint {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dd-{name}-{}", std::process::id()))
+    }
+
+    fn point(count_bytes: Option<u64>) -> Point {
+        Point {
+            input: "/dev/sda".to_string(),
+            output: "/tmp/image with spaces".to_string(),
+            ibs: 512,
+            obs: 4096,
+            input_offset: 1 << 33,
+            output_offset: 12345,
+            count_bytes,
+            fingerprint: "8388608:7791e7115f21a6e5".to_string(),
+        }
+    }
+
+    #[test]
+    fn point_round_trip() {
+        let path = temp("point");
+        for p in [point(None), point(Some(0)), point(Some(77))] {
+            p.save(&path);
+            let loaded = Point::load(&path);
+            assert_eq!(loaded.to_string(), p.to_string());
+            assert_eq!(loaded.count_bytes, p.count_bytes);
+        }
+        assert!(!PathBuf::from(format!("{}.tmp", path.display())).exists());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn fingerprint_covers_size_and_start() {
+        let path = temp("fingerprint");
+        let opts = crate::Options::from_args(["dd".to_string(), "--no-cfg".to_string(), format!("if={}", path.display())]);
+        let print = |data: &[u8]| {
+            std::fs::write(&path, data).unwrap();
+            fingerprint(&opts, &std::fs::File::open(&path).unwrap())
+        };
+
+        let mut data = vec![7u8; FINGERPRINT_LEN + 100];
+        let base = print(&data);
+        assert_eq!(base, format!("{}:{:016x}", data.len(), crate::io::fnv1a(crate::io::FNV_OFFSET, &data[..FINGERPRINT_LEN])));
+
+        // only the start is hashed
+        data[FINGERPRINT_LEN + 1] = 0;
+        assert_eq!(print(&data), base);
+        data[FINGERPRINT_LEN - 1] = 0;
+        assert_ne!(print(&data), base);
+        // the size catches changes to the rest
+        data[FINGERPRINT_LEN - 1] = 7;
+        data.push(7);
+        assert_ne!(print(&data), base);
+        let _ = std::fs::remove_file(path);
+    }
+}
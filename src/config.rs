@@ -22,7 +22,9 @@ pub struct ParsedCfg {
     /// Files in here will never be modified. Files in dirs here will not be modified.
     never: Vec<PathBuf>,
     /// Locations in here will never be created (dirs will not be created regardless).
-    no_create: Vec<PathBuf>
+    no_create: Vec<PathBuf>,
+    /// `key=value` lines from the `[scheduling]` section, overridden by the command line.
+    sched: crate::sched::Sched,
 }
 
 fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
@@ -30,13 +32,11 @@ fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
     let path = path.as_ref().to_path_buf();
     let path = PathBuf::from(&*shellexpand::full(path.to_str().unwrap()).unwrap());
 
-    let absolute_path = if path.is_absolute() {
+    if path.is_absolute() {
         path.to_path_buf()
     } else {
         std::env::current_dir().unwrap().join(path)
-    }.clean();
-
-    absolute_path
+    }.clean()
 }
 
 fn resolve_path<P: AsRef<Path>>(origin_path: P) -> std::io::Result<PathBuf> {
@@ -88,16 +88,20 @@ impl ParsedCfg {
         Self {
             never: Vec::new(),
             no_create: Vec::new(),
+            sched: crate::sched::Sched::default(),
         }
     }
 
     pub fn load(&mut self, path: PathBuf) {
         let file = throw_or(std::fs::read_to_string(&path),0x30, Some(&format!("Unable to read {}: ", path.display())));
         let mut tgt = None;
+        let mut sched = false;
         for i in file.split('\n') {
             match i {
-                "[never-ever]" => tgt = Some(&mut self.never),
-                "[no-create]" => tgt = Some(&mut self.no_create),
+                "[never-ever]" => (tgt, sched) = (Some(&mut self.never), false),
+                "[no-create]" => (tgt, sched) = (Some(&mut self.no_create), false),
+                "[scheduling]" => (tgt, sched) = (None, true),
+                f if sched && !f.trim().is_empty() => Self::load_sched(&mut self.sched, f, &path),
                 f if tgt.is_some() => {
                     if let Ok(p) = resolve_path(PathBuf::from(f)) {
                         let t = tgt.as_mut().unwrap();
@@ -109,6 +113,25 @@ impl ParsedCfg {
         }
     }
 
+    fn load_sched(sched: &mut crate::sched::Sched, line: &str, path: &Path) {
+        let invalid = || -> ! {
+            eprintln!("Invalid line in [scheduling] of {}: {line}", path.display());
+            std::process::exit(0x34);
+        };
+        let Some((k, v)) = line.split_once('=') else { invalid() };
+        let v = v.trim();
+        match k.trim() {
+            "ionice" => sched.ionice = Some(v.try_into().unwrap_or_else(|_| invalid())),
+            "nice" => sched.nice = Some(v.parse().unwrap_or_else(|_| invalid())),
+            "cpu-affinity" => sched.cpu_affinity = Some(crate::sched::parse_cpu_list(v).unwrap_or_else(|_| invalid())),
+            _ => invalid(),
+        }
+    }
+
+    pub fn sched(&self) -> crate::sched::Sched {
+        self.sched.clone()
+    }
+
     pub fn can_write(&self, path: &PathBuf) -> Result<bool,std::io::Error> {
         let cannon = resolve_path(path)?;
         for i in self.never.iter() {
@@ -121,7 +144,7 @@ impl ParsedCfg {
     }
 
     pub fn can_create(&self, path: &PathBuf) -> Result<bool, std::io::Error> {
-        let cannon = normalize_path(&path);
+        let cannon = normalize_path(path);
         for i in self.no_create.iter() {
             if cannon.starts_with(i) {
                 return Ok(false)
@@ -0,0 +1,94 @@
+//! Control socket for `--control`, used to change settings while copying.
+//!
+//! Accepts connections on a unix socket and reads one command per line, answering each with a
+//! line starting with `ok` or `error`. The only command is `rate-limit BYTES`, where `off` or 0
+//! removes the limit and no argument leaves it as is.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// Handle to the control socket, [Control::finish] removes it.
+pub struct Control {
+    path: PathBuf,
+}
+
+impl Control {
+    /// Binds the socket at `path` and serves it from a new thread.
+    /// A socket left behind by an earlier run is replaced, anything else at `path` exits with 0x13.
+    pub fn spawn(path: PathBuf) -> Self {
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            let _ = std::fs::remove_file(&path);
+        }
+        let listener = UnixListener::bind(&path)
+            .unwrap_or_else(|e| crate::handle_err(e, &format!("control socket {}", path.display()), 0x13));
+
+        crate::signal::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // one client at a time, commands are rare
+                let _ = serve(stream);
+            }
+        });
+        Self {
+            path,
+        }
+    }
+
+    pub fn finish(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream) -> std::io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = command(line?.trim());
+        writeln!(out, "{reply}")?;
+    }
+    Ok(())
+}
+
+fn command(line: &str) -> String {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("rate-limit"), arg) => {
+            match arg {
+                None => {}
+                Some("off") => crate::rate::set(0),
+                Some(s) => match crate::Options::try_parse_units(s) {
+                    Some(l) => crate::rate::set(l as u64),
+                    None => return format!("error: expected an integer and maybe a following unit eg. '50M', found {s}"),
+                },
+            }
+            match crate::rate::limit() {
+                Some(l) => format!("ok rate-limit {l}"),
+                None => "ok rate-limit off".to_string(),
+            }
+        }
+        (Some(c), _) => format!("error: unknown command {c}"),
+        (None, _) => "error: expected a command".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::command;
+
+    #[test]
+    fn rate_limit_commands() {
+        assert_eq!(command("rate-limit 50M"), "ok rate-limit 52428800");
+        // no argument reports the current limit
+        assert_eq!(command("rate-limit"), "ok rate-limit 52428800");
+        assert!(command("rate-limit 5Q").starts_with("error: expected an integer"));
+        assert_eq!(command("rate-limit"), "ok rate-limit 52428800");
+        assert_eq!(command("rate-limit off"), "ok rate-limit off");
+        assert_eq!(command("rate-limit 0"), "ok rate-limit off");
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(command("frobnicate 3"), "error: unknown command frobnicate");
+        assert_eq!(command(""), "error: expected a command");
+    }
+}
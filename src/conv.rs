@@ -0,0 +1,478 @@
+//! The data transforming conversions applied between reading and writing.
+
+use crate::flags::Conv;
+
+/// EBCDIC to ASCII, as used by `conv=ascii`
+const EBCDIC_TO_ASCII: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x9c, 0x09, 0x86, 0x7f,
+    0x97, 0x8d, 0x8e, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x9d, 0x85, 0x08, 0x87,
+    0x18, 0x19, 0x92, 0x8f, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x0a, 0x17, 0x1b,
+    0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x05, 0x06, 0x07,
+    0x90, 0x91, 0x16, 0x93, 0x94, 0x95, 0x96, 0x04,
+    0x98, 0x99, 0x9a, 0x9b, 0x14, 0x15, 0x9e, 0x1a,
+    0x20, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6,
+    0xa7, 0xa8, 0xd5, 0x2e, 0x3c, 0x28, 0x2b, 0x7c,
+    0x26, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf,
+    0xb0, 0xb1, 0x21, 0x24, 0x2a, 0x29, 0x3b, 0x7e,
+    0x2d, 0x2f, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7,
+    0xb8, 0xb9, 0xcb, 0x2c, 0x25, 0x5f, 0x3e, 0x3f,
+    0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf, 0xc0, 0xc1,
+    0xc2, 0x60, 0x3a, 0x23, 0x40, 0x27, 0x3d, 0x22,
+    0xc3, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+    0x68, 0x69, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9,
+    0xca, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70,
+    0x71, 0x72, 0x5e, 0xcc, 0xcd, 0xce, 0xcf, 0xd0,
+    0xd1, 0xe5, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78,
+    0x79, 0x7a, 0xd2, 0xd3, 0xd4, 0x5b, 0xd6, 0xd7,
+    0xd8, 0xd9, 0xda, 0xdb, 0xdc, 0xdd, 0xde, 0xdf,
+    0xe0, 0xe1, 0xe2, 0xe3, 0xe4, 0x5d, 0xe6, 0xe7,
+    0x7b, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+    0x48, 0x49, 0xe8, 0xe9, 0xea, 0xeb, 0xec, 0xed,
+    0x7d, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50,
+    0x51, 0x52, 0xee, 0xef, 0xf0, 0xf1, 0xf2, 0xf3,
+    0x5c, 0x9f, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58,
+    0x59, 0x5a, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37,
+    0x38, 0x39, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+/// ASCII to EBCDIC, as used by `conv=ebcdic`
+const ASCII_TO_EBCDIC: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x37, 0x2d, 0x2e, 0x2f,
+    0x16, 0x05, 0x25, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x3c, 0x3d, 0x32, 0x26,
+    0x18, 0x19, 0x3f, 0x27, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x40, 0x5a, 0x7f, 0x7b, 0x5b, 0x6c, 0x50, 0x7d,
+    0x4d, 0x5d, 0x5c, 0x4e, 0x6b, 0x60, 0x4b, 0x61,
+    0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7,
+    0xf8, 0xf9, 0x7a, 0x5e, 0x4c, 0x7e, 0x6e, 0x6f,
+    0x7c, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    0xc8, 0xc9, 0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6,
+    0xd7, 0xd8, 0xd9, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6,
+    0xe7, 0xe8, 0xe9, 0xad, 0xe0, 0xbd, 0x9a, 0x6d,
+    0x79, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96,
+    0x97, 0x98, 0x99, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6,
+    0xa7, 0xa8, 0xa9, 0xc0, 0x4f, 0xd0, 0x5f, 0x07,
+    0x20, 0x21, 0x22, 0x23, 0x24, 0x15, 0x06, 0x17,
+    0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x09, 0x0a, 0x1b,
+    0x30, 0x31, 0x1a, 0x33, 0x34, 0x35, 0x36, 0x08,
+    0x38, 0x39, 0x3a, 0x3b, 0x04, 0x14, 0x3e, 0xe1,
+    0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57,
+    0x58, 0x59, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+    0x68, 0x69, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75,
+    0x76, 0x77, 0x78, 0x80, 0x8a, 0x8b, 0x8c, 0x8d,
+    0x8e, 0x8f, 0x90, 0x6a, 0x9b, 0x9c, 0x9d, 0x9e,
+    0x9f, 0xa0, 0xaa, 0xab, 0xac, 0x4a, 0xae, 0xaf,
+    0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7,
+    0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xa1, 0xbe, 0xbf,
+    0xca, 0xcb, 0xcc, 0xcd, 0xce, 0xcf, 0xda, 0xdb,
+    0xdc, 0xdd, 0xde, 0xdf, 0xea, 0xeb, 0xec, 0xed,
+    0xee, 0xef, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+/// ASCII to IBM's alternate EBCDIC, as used by `conv=ibm`
+const ASCII_TO_IBM: [u8; 256] = [
+    0x00, 0x01, 0x02, 0x03, 0x37, 0x2d, 0x2e, 0x2f,
+    0x16, 0x05, 0x25, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x3c, 0x3d, 0x32, 0x26,
+    0x18, 0x19, 0x3f, 0x27, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x40, 0x5a, 0x7f, 0x7b, 0x5b, 0x6c, 0x50, 0x7d,
+    0x4d, 0x5d, 0x5c, 0x4e, 0x6b, 0x60, 0x4b, 0x61,
+    0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7,
+    0xf8, 0xf9, 0x7a, 0x5e, 0x4c, 0x7e, 0x6e, 0x6f,
+    0x7c, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    0xc8, 0xc9, 0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6,
+    0xd7, 0xd8, 0xd9, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6,
+    0xe7, 0xe8, 0xe9, 0xad, 0xe0, 0xbd, 0x5f, 0x6d,
+    0x79, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96,
+    0x97, 0x98, 0x99, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6,
+    0xa7, 0xa8, 0xa9, 0xc0, 0x4f, 0xd0, 0xa1, 0x07,
+    0x20, 0x21, 0x22, 0x23, 0x24, 0x15, 0x06, 0x17,
+    0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x09, 0x0a, 0x1b,
+    0x30, 0x31, 0x1a, 0x33, 0x34, 0x35, 0x36, 0x08,
+    0x38, 0x39, 0x3a, 0x3b, 0x04, 0x14, 0x3e, 0xe1,
+    0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57,
+    0x58, 0x59, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+    0x68, 0x69, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75,
+    0x76, 0x77, 0x78, 0x80, 0x8a, 0x8b, 0x8c, 0x8d,
+    0x8e, 0x8f, 0x90, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e,
+    0x9f, 0xa0, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf,
+    0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7,
+    0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf,
+    0xca, 0xcb, 0xcc, 0xcd, 0xce, 0xcf, 0xda, 0xdb,
+    0xdc, 0xdd, 0xde, 0xdf, 0xea, 0xeb, 0xec, 0xed,
+    0xee, 0xef, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+/// Applies the conversions selected by `conv=` to data as it is read.
+///
+/// Conversions are applied in the same order as GNU dd. A single translation table is built
+/// from the character set and case conversions which is applied first, followed by `swab` and then
+/// `block` or `unblock`. When translating to EBCDIC the newline and space characters used for
+/// blocking are translated too, which has the same effect as blocking before translating.
+pub struct Converter {
+    table: Option<[u8; 256]>,
+    swab: bool,
+    /// The byte held back from the last buffer by `swab`
+    saved: Option<Held>,
+    records: Option<Records>,
+    /// Output of `block` and `unblock`, kept to reuse its allocation
+    out: Vec<u8>,
+}
+
+/// A byte held back by `swab` between buffers.
+#[derive(Clone, Copy)]
+enum Held {
+    /// The odd byte at the end of the input so far, waiting for the byte it is swapped with
+    Unpaired(u8),
+    /// An already swapped byte which did not fit in the buffer
+    Swapped(u8),
+}
+
+#[derive(Eq, PartialEq)]
+enum RecordMode {
+    Block,
+    Unblock,
+}
+
+/// State for converting between newline terminated and fixed size records.
+/// Records may span many buffers so the position in the current record must be kept.
+struct Records {
+    mode: RecordMode,
+    cbs: usize,
+    col: usize,
+    pending_spaces: usize,
+    newline: u8,
+    space: u8,
+}
+
+impl Converter {
+    pub fn new(conv: &Conv, cbs: Option<usize>) -> Self {
+        let mut table = None;
+        let mut apply = |map: &dyn Fn(u8) -> u8| {
+            let t = table.get_or_insert_with(|| core::array::from_fn(|i| i as u8));
+            for i in t.iter_mut() {
+                *i = map(*i);
+            }
+        };
+
+        let mut newline = b'\n';
+        let mut space = b' ';
+
+        if conv.ascii {
+            apply(&|c| EBCDIC_TO_ASCII[c as usize]);
+        }
+        if conv.ucase {
+            apply(&|c| c.to_ascii_uppercase());
+        } else if conv.lcase {
+            apply(&|c| c.to_ascii_lowercase());
+        }
+        if conv.ebcdic {
+            apply(&|c| ASCII_TO_EBCDIC[c as usize]);
+            newline = ASCII_TO_EBCDIC[newline as usize];
+            space = ASCII_TO_EBCDIC[space as usize];
+        } else if conv.ibm {
+            apply(&|c| ASCII_TO_IBM[c as usize]);
+            newline = ASCII_TO_IBM[newline as usize];
+            space = ASCII_TO_IBM[space as usize];
+        }
+
+        // blocking does nothing without a record size
+        let records = cbs.filter(|c| *c > 0).and_then(|cbs| {
+            let mode = if conv.blocks() {
+                RecordMode::Block
+            } else if conv.unblocks() {
+                RecordMode::Unblock
+            } else {
+                return None
+            };
+
+            Some(Records {
+                mode,
+                cbs,
+                col: 0,
+                pending_spaces: 0,
+                newline,
+                space,
+            })
+        });
+
+        Self {
+            table,
+            swab: conv.swab,
+            saved: None,
+            records,
+            out: Vec::new(),
+        }
+    }
+
+    /// Returns whether any conversion modifies the data.
+    pub fn is_active(&self) -> bool {
+        self.table.is_some() || self.swab || self.records.is_some()
+    }
+
+    /// Returns whether the output depends on how the input is split into buffers, so the
+    /// input must be converted in order. Only the character set and case conversions do not.
+    pub fn is_ordered(&self) -> bool {
+        self.swab || self.records.is_some()
+    }
+
+    /// Applies the character set and case conversions to `buff`.
+    pub fn translate(&self, buff: &mut [u8]) {
+        if let Some(t) = &self.table {
+            for c in buff.iter_mut() {
+                *c = t[*c as usize];
+            }
+        }
+    }
+
+    /// Applies the character set, case and `swab` conversions to `buff` in place, returning the
+    /// length of the converted data at its start.
+    /// `swab` may hold back one byte until the next call, or [Self::finish].
+    pub fn convert(&mut self, buff: &mut [u8]) -> usize {
+        self.translate(buff);
+        if self.swab {
+            self.swab(buff)
+        } else {
+            buff.len()
+        }
+    }
+
+    /// Swaps every pair of bytes without moving any data out of `buff`.
+    fn swab(&mut self, buff: &mut [u8]) -> usize {
+        let n = buff.len();
+        let Some(held) = self.saved.take() else {
+            for pair in buff.chunks_exact_mut(2) {
+                pair.swap(0, 1);
+            }
+            if !n.is_multiple_of(2) {
+                self.saved = Some(Held::Unpaired(buff[n - 1]));
+                return n - 1;
+            }
+            return n;
+        };
+
+        // the held byte is written first, which moves every other byte up two places and
+        // pushes one off the end to be held instead
+        let (first, mut carry) = match held {
+            Held::Unpaired(c) => (1, c),
+            Held::Swapped(c) => (0, c),
+        };
+        for i in (first..n).step_by(2) {
+            std::mem::swap(&mut carry, &mut buff[i]);
+        }
+        self.saved = Some(match (held, n.is_multiple_of(2)) {
+            (Held::Unpaired(_), true) | (Held::Swapped(_), false) => Held::Unpaired(carry),
+            _ => Held::Swapped(carry),
+        });
+        n
+    }
+
+    /// Applies `block` or `unblock` to `buff`, which change the length of the data.
+    /// Returns `None` if neither was given.
+    pub fn reblock(&mut self, buff: &[u8]) -> Option<&[u8]> {
+        let r = self.records.as_mut()?;
+        self.out.clear();
+        r.convert(buff, &mut self.out);
+        Some(&self.out)
+    }
+
+    /// Returns any data still held by the converter at the end of the input.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // an unpaired byte at the end is left as it is
+        let saved: Vec<u8> = self.saved.take().map(|(Held::Unpaired(c) | Held::Swapped(c))| c).into_iter().collect();
+
+        if let Some(r) = &mut self.records {
+            r.convert(&saved, &mut out);
+            r.finish(&mut out);
+        } else {
+            out = saved;
+        }
+        out
+    }
+}
+
+impl Records {
+    fn convert(&mut self, buff: &[u8], out: &mut Vec<u8>) {
+        match self.mode {
+            RecordMode::Block => self.block(buff, out),
+            RecordMode::Unblock => self.unblock(buff, out),
+        }
+    }
+
+    /// Pads each line to `cbs` bytes, lines longer than `cbs` are truncated.
+    fn block(&mut self, buff: &[u8], out: &mut Vec<u8>) {
+        for &c in buff {
+            if c == self.newline {
+                if self.col < self.cbs {
+                    out.resize(out.len() + self.cbs - self.col, self.space);
+                }
+                self.col = 0;
+            } else {
+                if self.col == self.cbs {
+                    crate::STATE.truncated.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                } else if self.col < self.cbs {
+                    out.push(c);
+                }
+                self.col += 1;
+            }
+        }
+    }
+
+    /// Splits the input into `cbs` sized records, removes trailing spaces and appends a newline to each.
+    fn unblock(&mut self, buff: &[u8], out: &mut Vec<u8>) {
+        let mut i = 0;
+        while i < buff.len() {
+            let c = buff[i];
+            if self.col == self.cbs {
+                self.col = 0;
+                self.pending_spaces = 0;
+                out.push(self.newline);
+                // c starts the next record
+                continue;
+            }
+
+            self.col += 1;
+            if c == self.space {
+                self.pending_spaces += 1;
+            } else {
+                out.resize(out.len() + self.pending_spaces, self.space);
+                self.pending_spaces = 0;
+                out.push(c);
+            }
+            i += 1;
+        }
+    }
+
+    /// Completes the last record at the end of the input.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.col > 0 {
+            match self.mode {
+                RecordMode::Block => {
+                    if self.col < self.cbs {
+                        out.resize(out.len() + self.cbs - self.col, self.space);
+                    }
+                }
+                RecordMode::Unblock => out.push(self.newline),
+            }
+        }
+        self.col = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts `chunks` as if each was read separately, with the given `conv=` list.
+    fn convert(conv: &str, cbs: Option<usize>, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut c = Converter::new(&Conv::parse(&[conv]).unwrap(), cbs);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            let mut b = chunk.to_vec();
+            let n = c.convert(&mut b);
+            b.truncate(n);
+            match c.reblock(&b) {
+                Some(r) => out.extend(r),
+                None => out.extend(b),
+            }
+        }
+        out.extend(c.finish());
+        out
+    }
+
+    #[test]
+    fn ebcdic_table_entries() {
+        assert_eq!(convert("ebcdic", None, &[b"Aa0 \n"]), [0xc1, 0x81, 0xf0, 0x40, 0x25]);
+        assert_eq!(convert("ascii", None, &[&[0xc1, 0x81, 0xf0, 0x40, 0x25]]), b"Aa0 \n");
+    }
+
+    #[test]
+    fn ibm_differs_from_ebcdic() {
+        assert_eq!(convert("ebcdic", None, &[b"^~"]), [0x9a, 0x5f]);
+        assert_eq!(convert("ibm", None, &[b"^~"]), [0x5f, 0xa1]);
+        assert_eq!(convert("ibm", None, &[b"A!"]), convert("ebcdic", None, &[b"A!"]));
+    }
+
+    #[test]
+    fn ascii_ebcdic_round_trip() {
+        let all: Vec<u8> = (0..=255).collect();
+        let there = convert("ebcdic", None, &[&all]);
+        assert_eq!(convert("ascii", None, &[&there]), all);
+    }
+
+    #[test]
+    fn case_conversions() {
+        assert_eq!(convert("ucase", None, &[b"Hello, World!\xe4"]), b"HELLO, WORLD!\xe4");
+        assert_eq!(convert("lcase", None, &[b"Hello, World!\xc4"]), b"hello, world!\xc4");
+        // the case is changed after translating to ASCII and before translating from it
+        assert_eq!(convert("ascii,ucase", None, &[&[0x81, 0xc2]]), b"AB");
+        assert_eq!(convert("lcase,ebcdic", None, &[b"Ab"]), [0x81, 0x82]);
+    }
+
+    #[test]
+    fn swab_holds_odd_byte() {
+        assert_eq!(convert("swab", None, &[b"abc", b"de"]), b"badce");
+        assert_eq!(convert("swab", None, &[b"a", b"b", b"c"]), b"bac");
+        assert_eq!(convert("swab", None, &[b"abcd"]), b"badc");
+    }
+
+    #[test]
+    fn swab_across_any_split() {
+        let data = b"abcdefghijk";
+        for len in 0..=data.len() {
+            let expected: Vec<u8> = data[..len].chunks(2).flat_map(|p| p.iter().rev().copied()).collect();
+            // every way of splitting the data into reads
+            for cuts in 0..1u32 << len.saturating_sub(1) {
+                let mut chunks = Vec::new();
+                let mut start = 0;
+                for i in 1..len {
+                    if cuts & 1 << (i - 1) != 0 {
+                        chunks.push(&data[start..i]);
+                        start = i;
+                    }
+                }
+                chunks.push(&data[start..len]);
+                assert_eq!(convert("swab", None, &chunks), expected, "{chunks:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn block_pads_to_cbs() {
+        assert_eq!(convert("block", Some(4), &[b"ab\ncdef\n"]), b"ab  cdef");
+        // records may span reads
+        assert_eq!(convert("block", Some(4), &[b"a", b"b\nc", b"def\n"]), b"ab  cdef");
+        // nothing is blocked without cbs
+        assert_eq!(convert("block", None, &[b"ab\n"]), b"ab\n");
+    }
+
+    #[test]
+    fn block_counts_truncated_records() {
+        let truncated = || crate::STATE.truncated.load(std::sync::atomic::Ordering::Relaxed);
+        let before = truncated();
+        assert_eq!(convert("block", Some(4), &[b"abcdefg\nxy\n"]), b"abcdxy  ");
+        assert_eq!(truncated() - before, 1);
+    }
+
+    #[test]
+    fn block_final_record_without_newline() {
+        assert_eq!(convert("block", Some(4), &[b"ab\ncd"]), b"ab  cd  ");
+        assert_eq!(convert("ebcdic", Some(4), &[b"a\nb"]), [0x81, 0x40, 0x40, 0x40, 0x82, 0x40, 0x40, 0x40]);
+    }
+
+    #[test]
+    fn unblock_strips_trailing_spaces() {
+        assert_eq!(convert("unblock", Some(4), &[b"ab  cd  "]), b"ab\ncd\n");
+        assert_eq!(convert("unblock", Some(4), &[b"a b ", b"    "]), b"a b\n\n");
+        // a final record shorter than cbs still gets its newline
+        assert_eq!(convert("unblock", Some(4), &[b"ab  cd"]), b"ab\ncd\n");
+    }
+}
@@ -0,0 +1,155 @@
+//! Zero-copy transfer which lets the kernel move the data without passing it through userspace.
+//!
+//! Used when the data is copied unchanged in blocks of the same size. `copy_file_range` is used
+//! between files, `splice` when either end is a pipe and `sendfile` when writing to a socket.
+//! Each call moves up to one block, so the records are counted as by [crate::read_write].
+//!
+//! For stall detection each call is marked as busy on the side which can block inside it, the
+//! output for `copy_file_range` and `sendfile`. `splice` does not wait on pipes, they are
+//! polled afterwards on their own side, so only a file at the other end can block it.
+
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Method {
+    CopyFileRange,
+    Splice,
+    SendFile,
+}
+
+impl Method {
+    fn choose(input: &std::fs::File, output: &std::fs::File) -> Option<Self> {
+        let i = input.metadata().ok()?.file_type();
+        let o = output.metadata().ok()?.file_type();
+        if i.is_fifo() || o.is_fifo() {
+            Some(Self::Splice)
+        } else if o.is_socket() {
+            Some(Self::SendFile)
+        } else {
+            Some(Self::CopyFileRange)
+        }
+    }
+
+    /// Returns the side which a call can block on, if any.
+    fn blocking_side(self, input: &std::fs::File, output: &std::fs::File) -> Option<crate::watchdog::Side> {
+        let fifo = |f: &std::fs::File| f.metadata().is_ok_and(|m| m.file_type().is_fifo());
+        match self {
+            Self::Splice if fifo(output) => (!fifo(input)).then_some(crate::watchdog::Side::Read),
+            _ => Some(crate::watchdog::Side::Write),
+        }
+    }
+
+    /// Moves up to `len` bytes from the current position of `input` to `output`.
+    fn transfer(self, input: &std::fs::File, output: &std::fs::File, len: usize) -> std::io::Result<usize> {
+        let (i, o) = (input.as_raw_fd(), output.as_raw_fd());
+        // SAFETY: null offsets make the kernel use and update the file positions
+        let rc = unsafe {
+            match self {
+                Self::CopyFileRange => libc::copy_file_range(i, std::ptr::null_mut(), o, std::ptr::null_mut(), len, 0),
+                Self::Splice => libc::splice(i, std::ptr::null_mut(), o, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK),
+                Self::SendFile => libc::sendfile(o, i, std::ptr::null_mut(), len),
+            }
+        };
+        match rc {
+            rc if rc < 0 => Err(std::io::Error::last_os_error()),
+            rc => Ok(rc as usize),
+        }
+    }
+}
+
+/// Returns whether the options allow the data to be moved unchanged, one block at a time.
+fn eligible(opts: &crate::Options) -> bool {
+    let conv = crate::conv::Converter::new(&opts.conv, opts.cbs);
+    !conv.is_active()
+        && opts.i_bs == opts.o_bs
+        && !opts.i_flag.fullblock
+        && !opts.i_flag.direct
+        && !opts.i_flag.nocache
+        && !opts.o_flag.direct
+        && !opts.o_flag.nocache
+        // copy_file_range refuses O_APPEND outputs
+        && !opts.o_flag.append
+        // the data never passes through userspace to be hashed
+        && !crate::verify::recording()
+        && opts.hash.is_empty()
+}
+
+/// Copies from `input` to `output` without buffering the data in userspace.
+///
+/// Returns false without transferring anything if this is not possible, either because of the
+/// options or because the kernel does not support it for these files, the buffered pipeline
+/// should be used instead.
+pub fn copy(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File, checkpoint: &mut Option<crate::checkpoint::Checkpoint>) -> bool {
+    let Some(method) = eligible(opts).then(|| Method::choose(input, output)).flatten() else {
+        return false;
+    };
+    let s = &crate::STATE;
+    // a short read from a pipe is a partial record, anything else is only short because of the
+    // output, like a full pipe, so the rest of the block is moved before counting it
+    let whole_blocks = !input.metadata().is_ok_and(|m| m.file_type().is_fifo());
+    // only used to report where the output ran out of space
+    let o_base = std::io::Seek::stream_position(&mut &*output).unwrap_or(0);
+    let side = method.blocking_side(input, output);
+
+    // with iflag=count_bytes the count is the number of bytes left to read, otherwise it is in blocks
+    let mut remaining = opts.count.unwrap_or(usize::MAX);
+    let mut total = 0;
+    while remaining > 0 && !crate::signal::poll() {
+        let want = if opts.i_flag.count_bytes { remaining.min(opts.i_bs) } else { opts.i_bs };
+        let mut n = 0;
+        while n < want {
+            let busy = side.map(crate::watchdog::busy);
+            let rc = method.transfer(input, output, want - n);
+            drop(busy);
+            match rc {
+                Ok(0) => break,
+                Ok(done) => n += done,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => if crate::signal::poll() {
+                    break
+                },
+                // either end may be non-blocking, and splice does not wait on pipes
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    let busy = crate::watchdog::busy(crate::watchdog::Side::Write);
+                    crate::read_write::wait_ready(output, libc::POLLOUT);
+                    drop(busy);
+                    let _busy = crate::watchdog::busy(crate::watchdog::Side::Read);
+                    crate::read_write::wait_ready(input, libc::POLLIN);
+                }
+                Err(e) if total + n == 0 && matches!(e.raw_os_error(), Some(libc::EXDEV | libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)) => {
+                    #[cfg(debug)]
+                    eprintln!("{method:?} not supported: {e}");
+                    return false;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => crate::read_write::handle_write_err(e, opts, o_base + (total + n) as u64),
+                Err(e) => crate::handle_err(e, &format!("copying {} to {}", opts.i_f, opts.o_f), 0x21),
+            }
+            if !whole_blocks {
+                break
+            }
+        }
+        if n == 0 {
+            break
+        }
+
+        crate::rate::throttle(crate::rate::Side::Write, n);
+        remaining -= if opts.i_flag.count_bytes { n } else { 1 };
+        total += n;
+        s.bytes_read.fetch_add(n, Ordering::Relaxed);
+        s.bytes_written.fetch_add(n, Ordering::Relaxed);
+        if n < opts.i_bs {
+            s.read_extra.fetch_add(1, Ordering::Relaxed);
+        } else {
+            s.read_blk.fetch_add(1, Ordering::Relaxed);
+        }
+        // output records are counted as if reblocked to obs, like the buffered pipeline
+        s.write_blk.store(total / opts.o_bs, Ordering::Relaxed);
+        if let Some(c) = checkpoint {
+            c.update(output);
+        }
+    }
+
+    s.write_extra.store(!total.is_multiple_of(opts.o_bs), Ordering::Relaxed);
+    true
+}
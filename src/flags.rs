@@ -0,0 +1,201 @@
+//! Parsing for the comma separated symbol lists given to `conv=`, `iflag=` and `oflag=`.
+
+/// Splits every instance of a list argument on ',' and passes each symbol to `f`.
+/// Returns the first symbol `f` did not accept.
+fn for_each_symbol<S: AsRef<str>>(list: &[S], mut f: impl FnMut(&str) -> bool) -> Result<(), String> {
+    for sym in list.iter().flat_map(|s| s.as_ref().split(',')) {
+        if !f(sym) {
+            return Err(sym.to_string())
+        }
+    }
+    Ok(())
+}
+
+/// Conversions given with `conv=`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Conv {
+    /// Flush file data and metadata to disk before finishing.
+    pub fsync: bool,
+    /// Flush file data to disk before finishing.
+    pub fdatasync: bool,
+    /// Translate from EBCDIC to ASCII
+    pub ascii: bool,
+    /// Translate from ASCII to EBCDIC
+    pub ebcdic: bool,
+    /// Translate from ASCII to alternate EBCDIC
+    pub ibm: bool,
+    /// Change upper case to lower case
+    pub lcase: bool,
+    /// Change lower case to upper case
+    pub ucase: bool,
+    /// Swap every pair of input bytes
+    pub swab: bool,
+    /// Pad newline terminated records with spaces to cbs-size
+    pub block: bool,
+    /// Replace trailing spaces in cbs-size records with newline
+    pub unblock: bool,
+}
+
+impl Conv {
+    pub fn parse<S: AsRef<str>>(list: &[S]) -> Result<Self, String> {
+        let mut c = Self::default();
+        for_each_symbol(list, |s| {
+            match s {
+                "fsync" => c.fsync = true,
+                "fdatasync" => c.fdatasync = true,
+                "ascii" => c.ascii = true,
+                "ebcdic" => c.ebcdic = true,
+                "ibm" => c.ibm = true,
+                "lcase" => c.lcase = true,
+                "ucase" => c.ucase = true,
+                "swab" => c.swab = true,
+                "block" => c.block = true,
+                "unblock" => c.unblock = true,
+                _ => return false,
+            }
+            true
+        })?;
+        Ok(c)
+    }
+
+    /// Returns a pair of conversions which were both given but cannot be used together.
+    pub fn conflict(&self) -> Option<(&'static str, &'static str)> {
+        let exclusive: [&[(&str, bool)]; 3] = [
+            &[("ascii", self.ascii), ("ebcdic", self.ebcdic), ("ibm", self.ibm)],
+            &[("lcase", self.lcase), ("ucase", self.ucase)],
+            &[("block", self.blocks()), ("unblock", self.unblocks())],
+        ];
+
+        for set in exclusive {
+            let mut given = set.iter().filter(|(_, b)| *b);
+            if let (Some((a, _)), Some((b, _))) = (given.next(), given.next()) {
+                return Some((a, b))
+            }
+        }
+        None
+    }
+
+    /// Whether records are blocked when `cbs` is given, `ebcdic` and `ibm` imply `block`.
+    pub fn blocks(&self) -> bool {
+        self.block || self.ebcdic || self.ibm
+    }
+
+    /// Whether records are unblocked when `cbs` is given, `ascii` implies `unblock`.
+    pub fn unblocks(&self) -> bool {
+        self.unblock || self.ascii
+    }
+}
+
+/// Flags given with `iflag=`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IFlag {
+    /// Keep reading until a full input block is gathered
+    pub fullblock: bool,
+    /// `skip=` is given in bytes rather than blocks
+    pub skip_bytes: bool,
+    /// `count=` is given in bytes rather than blocks
+    pub count_bytes: bool,
+    /// Open the input with `O_DIRECT`
+    pub direct: bool,
+    /// Drop data from the page cache after it has been read
+    pub nocache: bool,
+    /// Open the input with `O_NOATIME`
+    pub noatime: bool,
+    /// Open the input with `O_NONBLOCK`
+    pub nonblock: bool,
+}
+
+impl IFlag {
+    pub fn parse<S: AsRef<str>>(list: &[S]) -> Result<Self, String> {
+        let mut i = Self::default();
+        for_each_symbol(list, |s| {
+            match s {
+                "fullblock" => i.fullblock = true,
+                "skip_bytes" => i.skip_bytes = true,
+                "count_bytes" => i.count_bytes = true,
+                "direct" => i.direct = true,
+                "nocache" => i.nocache = true,
+                "noatime" => i.noatime = true,
+                "nonblock" => i.nonblock = true,
+                _ => return false,
+            }
+            true
+        })?;
+        Ok(i)
+    }
+
+    /// Returns the flags which must be passed to `open(2)`
+    pub fn open_flags(&self) -> i32 {
+        let mut f = 0;
+        if self.direct {
+            f |= libc::O_DIRECT;
+        }
+        if self.noatime {
+            f |= libc::O_NOATIME;
+        }
+        if self.nonblock {
+            f |= libc::O_NONBLOCK;
+        }
+        f
+    }
+}
+
+/// Flags given with `oflag=`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OFlag {
+    /// Open the output with `O_SYNC`
+    pub sync: bool,
+    /// Open the output with `O_DSYNC`
+    pub dsync: bool,
+    /// `seek=` is given in bytes rather than blocks
+    pub seek_bytes: bool,
+    /// Open the output with `O_DIRECT`
+    pub direct: bool,
+    /// Drop data from the page cache after it has been written and synced
+    pub nocache: bool,
+    /// Open the output with `O_APPEND`
+    pub append: bool,
+    /// Open the output with `O_NONBLOCK`
+    pub nonblock: bool,
+}
+
+impl OFlag {
+    pub fn parse<S: AsRef<str>>(list: &[S]) -> Result<Self, String> {
+        let mut o = Self::default();
+        for_each_symbol(list, |s| {
+            match s {
+                "sync" => o.sync = true,
+                "dsync" => o.dsync = true,
+                "seek_bytes" => o.seek_bytes = true,
+                "direct" => o.direct = true,
+                "nocache" => o.nocache = true,
+                "append" => o.append = true,
+                "nonblock" => o.nonblock = true,
+                _ => return false,
+            }
+            true
+        })?;
+        Ok(o)
+    }
+
+    /// Returns the flags which must be passed to `open(2)`
+    pub fn open_flags(&self) -> i32 {
+        let mut f = 0;
+        if self.sync {
+            f |= libc::O_SYNC;
+        }
+        if self.dsync {
+            f |= libc::O_DSYNC;
+        }
+        if self.direct {
+            f |= libc::O_DIRECT;
+        }
+        if self.append {
+            f |= libc::O_APPEND;
+        }
+        if self.nonblock {
+            f |= libc::O_NONBLOCK;
+        }
+        f
+    }
+}
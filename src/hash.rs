@@ -0,0 +1,241 @@
+//! Inline hashing for `--hash`.
+//!
+//! The input is hashed as it is read and the output as each block is written, so the digests
+//! of both sides reflect any conversions without a second pass over the data. The output
+//! digest only covers what reached the output, which is less than the input when a transfer is
+//! interrupted with `--on-interrupt discard`.
+
+use std::sync::Mutex;
+
+use sha2::Digest;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Blake3,
+    Crc32,
+    Md5,
+}
+
+impl TryFrom<&str> for Algorithm {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match &*value.to_lowercase() {
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            "crc32" => Ok(Self::Crc32),
+            "md5" => Ok(Self::Md5),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Algorithm {
+    /// Parses the comma separated list given to `--hash`, returning the first unknown name.
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, String> {
+        let mut algorithms = Vec::new();
+        for name in list.split(',') {
+            let a = Self::try_from(name).map_err(|_| name.to_string())?;
+            if !algorithms.contains(&a) {
+                algorithms.push(a);
+            }
+        }
+        Ok(algorithms)
+    }
+
+    /// Returns whether `sha256sum -c`, `md5sum -c` or `cksum -c` can check a digest file with
+    /// this algorithm, see [write_file].
+    pub fn checkable(self) -> bool {
+        matches!(self, Self::Sha256 | Self::Md5)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+            Self::Crc32 => "crc32",
+            Self::Md5 => "md5",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+    Md5(md5::Md5),
+}
+
+impl Hasher {
+    fn new(a: Algorithm) -> Self {
+        match a {
+            Algorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            Algorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+            Algorithm::Md5 => Self::Md5(md5::Md5::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+            Self::Crc32(h) => h.update(data),
+            Self::Md5(h) => h.update(data),
+        }
+    }
+
+    /// Returns the digest of the data so far in hex, the hasher can still be updated afterwards.
+    fn hex(&self) -> String {
+        let bytes = match self.clone() {
+            Self::Sha256(h) => h.finalize().to_vec(),
+            Self::Blake3(h) => h.finalize().as_bytes().to_vec(),
+            Self::Crc32(h) => h.finalize().to_be_bytes().to_vec(),
+            Self::Md5(h) => h.finalize().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+struct Hashers {
+    algorithms: Vec<Algorithm>,
+    input: Vec<Hasher>,
+    output: Vec<Hasher>,
+}
+
+static HASHERS: Mutex<Option<Hashers>> = Mutex::new(None);
+
+/// Starts hashing with `algorithms`.
+pub fn init(algorithms: &[Algorithm]) {
+    if algorithms.is_empty() {
+        return;
+    }
+    let new = || algorithms.iter().map(|a| Hasher::new(*a)).collect::<Vec<_>>();
+    *HASHERS.lock().unwrap_or_else(|e| e.into_inner()) = Some(Hashers {
+        algorithms: algorithms.to_vec(),
+        input: new(),
+        output: new(),
+    });
+}
+
+/// Hashes `data` as read from the input.
+pub fn input(data: &[u8]) {
+    if let Some(h) = HASHERS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        h.input.iter_mut().for_each(|h| h.update(data));
+    }
+}
+
+/// Hashes `data` once it has been written to the output.
+pub fn output(data: &[u8]) {
+    if let Some(h) = HASHERS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        h.output.iter_mut().for_each(|h| h.update(data));
+    }
+}
+
+/// Returns the name of every algorithm with the digests of the input and output so far.
+fn digests() -> Vec<(Algorithm, String, String)> {
+    let guard = HASHERS.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(h) = guard.as_ref() else {
+        return Vec::new();
+    };
+    h.algorithms.iter().enumerate().map(|(i, &a)| (a, h.input[i].hex(), h.output[i].hex())).collect()
+}
+
+/// Writes a line with each digest of the input and output for the summary.
+pub fn write_summary(f: &mut impl std::fmt::Write) -> std::fmt::Result {
+    for (a, input, output) in digests() {
+        writeln!(f, "{} of input:  {input}", a.name())?;
+        writeln!(f, "{} of output: {output}", a.name())?;
+    }
+    Ok(())
+}
+
+/// Returns the digests as a JSON object with an `input` and `output` object, if hashing.
+pub fn json() -> Option<String> {
+    let d = digests();
+    if d.is_empty() {
+        return None;
+    }
+    let side = |output: bool| {
+        let fields: Vec<String> = d.iter().map(|(a, i, o)| format!("\"{}\":\"{}\"", a.name(), if output { o } else { i })).collect();
+        format!("{{{}}}", fields.join(","))
+    };
+    Some(format!("{{\"input\":{},\"output\":{}}}", side(false), side(true)))
+}
+
+/// Writes the digests to `path` in the format of `sha256sum` or `md5sum`, or of their `--tag`
+/// format when both are used, so `sha256sum -c`, `md5sum -c` or `cksum -c` can check the files
+/// later. Only [Algorithm::checkable] digests are written, the others are left to the summary,
+/// and without any there is no file. stdin and stdout are named `-`.
+pub fn write_file(path: &std::path::Path, opts: &crate::Options) {
+    let name = |t: &crate::io::Target| match t {
+        crate::io::Target::Path(p) => p.display().to_string(),
+        _ => "-".to_string(),
+    };
+    let d: Vec<_> = digests().into_iter().filter(|(a, ..)| a.checkable()).collect();
+    if d.is_empty() {
+        return;
+    }
+    let mut out = String::new();
+    for (algorithm, input, output) in &d {
+        for (digest, target) in [(input, &opts.i_f), (output, &opts.o_f)] {
+            if d.len() == 1 {
+                out += &format!("{digest}  {}\n", name(target));
+            } else {
+                out += &format!("{} ({}) = {digest}\n", algorithm.name().to_uppercase(), name(target));
+            }
+        }
+    }
+    std::fs::write(path, out).unwrap_or_else(|e| crate::handle_err(e, &format!("hash file {}", path.display()), 0x14));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn algorithm_lists() {
+        assert_eq!(Algorithm::parse_list("sha256"), Ok(vec![Algorithm::Sha256]));
+        assert_eq!(Algorithm::parse_list("MD5,blake3,crc32"), Ok(vec![Algorithm::Md5, Algorithm::Blake3, Algorithm::Crc32]));
+        // repeats are only hashed once
+        assert_eq!(Algorithm::parse_list("sha256,md5,SHA256"), Ok(vec![Algorithm::Sha256, Algorithm::Md5]));
+        assert_eq!(Algorithm::parse_list("sha256,sha1"), Err("sha1".to_string()));
+        assert_eq!(Algorithm::parse_list("sha256,"), Err(String::new()));
+        assert_eq!(Algorithm::parse_list(""), Err(String::new()));
+    }
+
+    /// Returns the digest of `data` fed in pieces of `step` bytes.
+    fn digest(a: Algorithm, data: &[u8], step: usize) -> String {
+        let mut h = Hasher::new(a);
+        data.chunks(step).for_each(|c| h.update(c));
+        h.hex()
+    }
+
+    #[test]
+    fn known_digests() {
+        let vectors = [
+            (Algorithm::Sha256, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (Algorithm::Md5, "d41d8cd98f00b204e9800998ecf8427e", "900150983cd24fb0d6963f7d28e17f72"),
+            (Algorithm::Crc32, "00000000", "352441c2"),
+            (Algorithm::Blake3, "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262", "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+        ];
+        for (a, empty, abc) in vectors {
+            assert_eq!(digest(a, b"", 1), empty, "{}", a.name());
+            assert_eq!(digest(a, b"abc", 3), abc, "{}", a.name());
+            assert_eq!(digest(a, b"abc", 1), abc, "{}", a.name());
+        }
+    }
+
+    #[test]
+    fn hex_does_not_finish() {
+        let mut h = Hasher::new(Algorithm::Sha256);
+        h.update(b"ab");
+        let _ = h.hex();
+        h.update(b"c");
+        assert_eq!(h.hex(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -1,4 +1,5 @@
-use std::os::fd::FromRawFd;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
 
 /// Contains info on the target which can be used to open a file.
 #[derive(Clone, Debug)]
@@ -20,15 +21,15 @@ fn handle_fs_err(path: &std::path::Path, err: std::io::Error) -> ! {
         unknown = false;
     }
     let msg = err.to_string();
-    if msg.len() > 0 {
+    if !msg.is_empty() {
         if unknown {
             eprint!(": {msg}");
         } else {
             eprint!(", {msg}");
         }
     }
-    eprint!("\n");
-    std::process::exit(0x11);
+    eprintln!();
+    super::exit_on_err(0x11);
 }
 #[derive(Eq, PartialEq)]
 pub enum IoMode {
@@ -38,10 +39,31 @@ pub enum IoMode {
 
 impl Target {
     pub fn open(&self, mode: IoMode, opts: &super::Options) -> std::fs::File {
+        let flags = match mode {
+            IoMode::Read => opts.i_flag.open_flags(),
+            IoMode::Write => opts.o_flag.open_flags(),
+        };
 
+        let f = match self.open_with(&mode, opts, flags) {
+            // tmpfs and some other filesystems reject O_DIRECT, buffered IO still works there
+            Err(e) if flags & libc::O_DIRECT != 0 && e.raw_os_error() == Some(libc::EINVAL) => {
+                info!("{self} does not support direct IO, continuing without it");
+                self.open_with(&mode, opts, flags & !libc::O_DIRECT)
+            }
+            r => r,
+        };
+
+        f.unwrap_or_else(|e| match (self, mode) {
+            (Target::Path(p), IoMode::Read) => handle_fs_err(p, e),
+            (_, IoMode::Write) => super::handle_err(e, &format!("in file {self:?}"),0x10),
+            _ => super::handle_err(e, &format!("setting flags on {self}"),0x12),
+        })
+    }
+
+    fn open_with(&self, mode: &IoMode, opts: &super::Options, flags: i32) -> std::io::Result<std::fs::File> {
         match self {
             Target::Path(p) => {
-                if mode == IoMode::Write {
+                if *mode == IoMode::Write {
                     if !opts.cfg.can_write(p).unwrap_or_else(|e| super::handle_err(e,&format!("Failed to open {}", p.display()),0x32)) {
                         eprintln!("Config prevents writing to {}",p.display());
                         std::process::exit(0x33);
@@ -55,18 +77,144 @@ impl Target {
                     }
 
                     let mut o = std::fs::OpenOptions::new();
-                    o.write(true).create(true);
-                    o.open(p).unwrap_or_else(|e| super::handle_err(e, &format!("in file {self:?}"),0x10))
+                    o.write(true).create(true).custom_flags(flags);
+                    o.open(p)
                 } else {
-                    std::fs::File::open(p).unwrap_or_else(|e| handle_fs_err(&p,e) )
+                    std::fs::OpenOptions::new().read(true).custom_flags(flags).open(p)
                 }
             }
-            Target::StdIn => unsafe { std::fs::File::from_raw_fd(FD_STDIN) },
-            Target::StdOut => unsafe { std::fs::File::from_raw_fd(FD_STDOUT) },
+            Target::StdIn => {
+                set_fd_flags(FD_STDIN, flags)?;
+                Ok(unsafe { std::fs::File::from_raw_fd(FD_STDIN) })
+            },
+            Target::StdOut => {
+                set_fd_flags(FD_STDOUT, flags)?;
+                Ok(unsafe { std::fs::File::from_raw_fd(FD_STDOUT) })
+            },
         }
     }
 }
 
+/// Adds `flags` to the status flags of an already open fd.
+/// Some flags, notably `O_SYNC` on Linux, can only be set by `open(2)`, those are silently ignored by the kernel.
+fn set_fd_flags(fd: std::os::fd::RawFd, flags: i32) -> std::io::Result<()> {
+    if flags == 0 {
+        return Ok(());
+    }
+    // SAFETY: fcntl does not touch memory, an invalid fd will only return an error.
+    unsafe {
+        let cur = libc::fcntl(fd, libc::F_GETFL);
+        if cur < 0 || libc::fcntl(fd, libc::F_SETFL, cur | flags) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Removes `O_DIRECT` from `f`, used for the final block which may not be a multiple of the logical block size.
+pub fn clear_direct(f: &std::fs::File) -> std::io::Result<()> {
+    let fd = f.as_raw_fd();
+    // SAFETY: fcntl does not touch memory, an invalid fd will only return an error.
+    unsafe {
+        let cur = libc::fcntl(fd, libc::F_GETFL);
+        if cur < 0 || libc::fcntl(fd, libc::F_SETFL, cur & !libc::O_DIRECT) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `f` is currently open with `O_DIRECT`.
+pub fn is_direct(f: &std::fs::File) -> bool {
+    // SAFETY: fcntl does not touch memory, an invalid fd will only return an error.
+    let cur = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFL) };
+    cur >= 0 && cur & libc::O_DIRECT != 0
+}
+
+/// Returns the logical block size of `f`.
+/// For block devices this is queried from the device, otherwise the page size is assumed
+/// which is at least as large as the block size of any filesystem which supports direct IO.
+pub fn logical_block_size(f: &std::fs::File) -> usize {
+    use std::os::unix::fs::FileTypeExt;
+    if f.metadata().is_ok_and(|m| m.file_type().is_block_device()) {
+        let mut size: libc::c_int = 0;
+        // SAFETY: BLKSSZGET writes a single int
+        if unsafe { libc::ioctl(f.as_raw_fd(), libc::BLKSSZGET, &mut size) } == 0 && size > 0 {
+            return size as usize;
+        }
+    }
+    crate::buffer::alignment()
+}
+
+/// Returns whether `f` can be read or written at any offset, as regular files and block devices can.
+pub fn seekable(f: &std::fs::File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    f.metadata().is_ok_and(|m| m.is_file() || m.file_type().is_block_device())
+}
+
+/// Reads from `offset` until `buff` is full or the end of `f` is reached.
+pub fn read_at(f: &std::fs::File, buff: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    let mut len = 0;
+    while len < buff.len() {
+        match f.read_at(&mut buff[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Continues the FNV-1a `hash` over `data`, which is fast enough to keep up with most devices
+/// and only has to catch accidental differences, not tampering.
+pub fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The shift of the direction bits in an ioctl number and the value of `_IOC_READ`.
+#[cfg(any(target_arch = "mips", target_arch = "mips32r6", target_arch = "mips64", target_arch = "mips64r6", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc", target_arch = "sparc64"))]
+const IOC_READ: (u32, u32) = (29, 2);
+#[cfg(not(any(target_arch = "mips", target_arch = "mips32r6", target_arch = "mips64", target_arch = "mips64r6", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc", target_arch = "sparc64")))]
+const IOC_READ: (u32, u32) = (30, 2);
+
+/// `_IOR(0x12, 114, size_t)`, missing from the libc crate.
+/// The size is that of `size_t` even though the kernel always writes a u64.
+const BLKGETSIZE64: libc::Ioctl = (IOC_READ.1 << IOC_READ.0 | (std::mem::size_of::<libc::size_t>() as u32) << 16 | 0x12 << 8 | 114) as libc::Ioctl;
+
+/// Returns the size of `f` in bytes or `None` if it can not be determined, as for pipes.
+/// Regular files use their metadata, block devices are queried with `BLKGETSIZE64` and
+/// anything else falls back to seeking to the end.
+pub fn size(f: &std::fs::File) -> Option<u64> {
+    use std::os::unix::fs::FileTypeExt;
+    let m = f.metadata().ok()?;
+    if m.is_file() {
+        return Some(m.len());
+    }
+    if m.file_type().is_block_device() {
+        let mut size: u64 = 0;
+        // SAFETY: BLKGETSIZE64 writes a single u64
+        if unsafe { libc::ioctl(f.as_raw_fd(), BLKGETSIZE64, &mut size) } == 0 {
+            return Some(size);
+        }
+    }
+
+    // SAFETY: lseek does not touch memory, the position is restored before returning
+    unsafe {
+        let cur = libc::lseek(f.as_raw_fd(), 0, libc::SEEK_CUR);
+        if cur < 0 {
+            return None;
+        }
+        let end = libc::lseek(f.as_raw_fd(), 0, libc::SEEK_END);
+        libc::lseek(f.as_raw_fd(), cur, libc::SEEK_SET);
+        // character devices such as /dev/zero report an end of 0
+        (end > 0).then_some(end as u64)
+    }
+}
+
 impl std::fmt::Display for Target {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -75,4 +223,13 @@ impl std::fmt::Display for Target {
             Target::StdOut => write!(f, "stdout"),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "x86", target_arch = "arm"))]
+    fn blkgetsize64_matches_linux_headers() {
+        let expected = if cfg!(target_pointer_width = "64") { 0x80081272 } else { 0x80041272 };
+        assert_eq!(super::BLKGETSIZE64 as u32, expected);
+    }
+}
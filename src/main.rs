@@ -1,51 +1,139 @@
 use std::env;
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize};
 
 const DEFAULT_CONFIG: &str = "/etc/disk-destroyer.conf";
 static STATE: GlobalState = GlobalState::new();
 
+/// Prints an informational message to stderr unless `status=none` was given.
+/// Errors should always use `eprintln!`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if crate::STATE.status() != crate::Status::None {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
 // rc's
 // 0x0?: See Options::new
-// 0x1?: FS problem
-// 0x2?: IO problem see read_write
-// 0x3?: Config Error
-// 0x130: sigint
+// 0x1?: FS problem, 0x13 is the control socket and 0x14 the hash file
+// 0x2?: IO problem see read_write, 0x24 is running out of space, 0x25 is a stall see watchdog
+// 0x3?: Config Error, 0x34 is an invalid scheduling setting
+// 0x4?: Checkpoint problem, see checkpoint
+// 0x5?: Verification, 0x50 is a mismatch and 0x51 means the output could not be read back, see verify
+// 128 + n: stopped by signal n, see signal
 
 mod io;
 mod read_write;
 mod config;
+mod conv;
+mod flags;
+mod buffer;
+mod progress;
+mod stats;
+mod signal;
+mod fast;
+mod parallel;
+mod checkpoint;
+mod rate;
+mod control;
+mod watchdog;
+mod sched;
+mod verify;
+mod hash;
+#[cfg(feature = "uring")]
+mod uring;
 
 fn main() {
     let o = Options::new();
-    let (tx,rx) = std::sync::mpsc::channel();
-
-    unsafe { signal_hook::low_level::register(signal_hook::consts::SIGINT, sigint).expect("Error installing interrupt handler") };
-    unsafe { signal_hook::low_level::register(signal_hook::consts::SIGUSR1, sigusr1).expect("Error installing interrupt handler") };
+    STATE.status.store(o.status as u8, std::sync::atomic::Ordering::Relaxed);
+    stats::init(o.stats_format, o.stats_fd);
+    // before starting any threads so they all inherit it
+    o.sched.apply();
+    hash::init(&o.hash);
+    let (pool, recycle) = buffer::Pool::new(o.buffer_size, IoQueue::capacity(o.i_bs, o.o_bs));
+    // every buffer in flight comes from the pool, so sending never blocks
+    let (tx,rx) = std::sync::mpsc::sync_channel(pool.max());
+
+    signal::install(o.on_interrupt);
+    rate::set(o.rate_limit.unwrap_or(0));
+    let control = o.control.clone().map(control::Control::spawn);
 
     STATE.cfg_time();
+    let progress = (o.status == Status::Progress).then(progress::Progress::spawn);
+
+    let input = read_write::open_input(&o);
+    let output = read_write::open_output(&o);
+    let mut checkpoint = checkpoint::Checkpoint::start(&o, &input, &output);
+    let verify = verify::Verify::start(&o, &input, &output);
+    if let Some(t) = o.stall_timeout {
+        watchdog::spawn(&o, t);
+    }
 
-    let write_thread = {
-        let options_send = o.clone();
-        std::thread::spawn(move || read_write::dd_write(options_send,rx))
-    };
+    #[cfg(feature = "uring")]
+    let uring = |o: &Options, i: &std::fs::File, out: &std::fs::File| uring::copy(o, i, out);
+    #[cfg(not(feature = "uring"))]
+    let uring = |_: &Options, _: &std::fs::File, _: &std::fs::File| false;
+
+    if parallel::copy(&o, &input, &output) || fast::copy(&o, &input, &output, &mut checkpoint) || uring(&o, &input, &output) {
+        read_write::sync_output(&o, &output);
+        if let Some(c) = checkpoint {
+            c.finish(&output);
+        }
+    } else {
+        let write_thread = {
+            let options_send = o.clone();
+            signal::spawn(move || read_write::dd_write(options_send,output,rx,recycle,checkpoint))
+        };
 
-    read_write::dd_read(o.clone(),tx);
-    drop(write_thread.join());
+        read_write::dd_read(o.clone(),input,tx,pool);
+        drop(write_thread.join());
+    }
 
-    if o.status != Status::NoXFer {
-        eprintln!("{}", STATE);
+    if let Some(p) = progress {
+        p.finish();
+    }
+    if let Some(c) = control {
+        c.finish();
     }
-}
 
-fn sigint() {
-    eprintln!("{}",STATE);
-    std::process::exit(130);
+    if let Some(sig) = signal::pending() {
+        STATE.exit_reason.store(ExitReason::Interrupted as u8, std::sync::atomic::Ordering::Relaxed);
+        print_stats();
+        std::process::exit(128 + sig);
+    }
+    print_stats();
+    if let Some(p) = &o.hash_file {
+        hash::write_file(p, &o);
+    }
+
+    if let Some(v) = verify {
+        let ok = v.run(&o);
+        if let Some(sig) = signal::pending() {
+            std::process::exit(128 + sig);
+        }
+        if !ok {
+            std::process::exit(0x50);
+        }
+    }
 }
 
-fn sigusr1() {
-    eprintln!("{}", STATE);
+/// Prints the transfer statistics as allowed by `status=`
+fn print_stats() {
+    match (STATE.status(), stats::format()) {
+        (Status::None, _) => {}
+        (_, stats::Format::Json) => stats::emit(&stats::json("summary")),
+        (Status::NoXFer, stats::Format::Gnu) | (Status::NoXFer, stats::Format::Human) => {
+            let mut s = String::new();
+            let _ = STATE.write_records(&mut s);
+            let _ = hash::write_summary(&mut s);
+            stats::emit(&s);
+        }
+        (_, stats::Format::Gnu) => stats::emit(&stats::gnu()),
+        (Status::Default | Status::Progress, stats::Format::Human) => stats::emit(&format!("{STATE}\n")),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -58,44 +146,133 @@ pub struct Options {
     o_skip: Option<usize>,
     i_skip: Option<usize>,
     status: Status,
+    stats_format: stats::Format,
+    stats_fd: Option<std::os::fd::RawFd>,
+    on_interrupt: signal::Policy,
+    buffer_size: usize,
+    #[cfg_attr(not(feature = "uring"), allow(dead_code))]
+    queue_depth: usize,
+    jobs: usize,
+    size_hint: Option<u64>,
+    /// Where the transfer is checkpointed, given by `--checkpoint` or else `--resume`
+    checkpoint: Option<PathBuf>,
+    resume: Option<checkpoint::Point>,
+    rate_limit: Option<u64>,
+    control: Option<PathBuf>,
+    stall_timeout: Option<std::time::Duration>,
+    sched: sched::Sched,
+    verify: bool,
+    hash: Vec<hash::Algorithm>,
+    hash_file: Option<PathBuf>,
+    conv: flags::Conv,
+    cbs: Option<usize>,
+    i_flag: flags::IFlag,
+    o_flag: flags::OFlag,
     cfg: config::ParsedCfg
 }
 
 struct GlobalState {
-    queued: AtomicUsize,
+    /// The [Status] given by `status=`
+    status: AtomicU8,
 
     read_blk: AtomicUsize,
-    read_extra: AtomicBool,
+    /// Number of partial records read
+    read_extra: AtomicUsize,
 
     write_blk: AtomicUsize,
     write_extra: AtomicBool,
     // this should ever be poisoned. it is only locked by the main thread and during an interrupt.
-    started: std::sync::RwLock<Option<std::time::SystemTime>>,
+    started: std::sync::RwLock<Option<std::time::Instant>>,
     bytes_written: AtomicUsize,
+    bytes_read: AtomicUsize,
+    /// Number of bytes expected to be read, 0 if unknown.
+    expected: AtomicU64,
+    /// Bytes of input passed over for `skip=`
+    skipped: AtomicU64,
+    /// Records truncated by `conv=block`
+    truncated: AtomicUsize,
+    /// Time spent flushing the output for `conv=fsync` or `conv=fdatasync` in nanoseconds.
+    sync_time: AtomicU64,
+    /// Whether the transfer ended with an error. Every error ends it, so there is never more than one.
+    failed: AtomicBool,
+    /// The [ExitReason] reported in the statistics
+    exit_reason: AtomicU8,
 }
 
 impl GlobalState {
     const fn new() -> Self {
         Self{
-            queued: AtomicUsize::new(0),
+            status: AtomicU8::new(Status::Default as u8),
             read_blk: AtomicUsize::new(0),
-            read_extra: AtomicBool::new(false),
+            read_extra: AtomicUsize::new(0),
             write_blk: AtomicUsize::new(0),
             write_extra: AtomicBool::new(false),
             started: std::sync::RwLock::new(None),
             bytes_written: AtomicUsize::new(0),
+            bytes_read: AtomicUsize::new(0),
+            expected: AtomicU64::new(0),
+            truncated: AtomicUsize::new(0),
+            skipped: AtomicU64::new(0),
+            sync_time: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+            exit_reason: AtomicU8::new(ExitReason::Complete as u8),
         }
     }
 
     fn cfg_time(&self) {
-        *self.started.write().unwrap() = Some(std::time::SystemTime::now())
+        *self.started.write().unwrap() = Some(std::time::Instant::now())
+    }
+
+    fn status(&self) -> Status {
+        match self.status.load(std::sync::atomic::Ordering::Relaxed) {
+            s if s == Status::None as u8 => Status::None,
+            s if s == Status::NoXFer as u8 => Status::NoXFer,
+            s if s == Status::Progress as u8 => Status::Progress,
+            _ => Status::Default,
+        }
+    }
+
+    fn exit_reason(&self) -> ExitReason {
+        match self.exit_reason.load(std::sync::atomic::Ordering::Relaxed) {
+            r if r == ExitReason::Error as u8 => ExitReason::Error,
+            r if r == ExitReason::Interrupted as u8 => ExitReason::Interrupted,
+            r if r == ExitReason::Stalled as u8 => ExitReason::Stalled,
+            _ => ExitReason::Complete,
+        }
+    }
+
+    /// Writes the records in, records out and truncated records lines
+    fn write_records(&self, f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        writeln!(f, "{}+{} records in", self.read_blk.load(std::sync::atomic::Ordering::Relaxed), self.read_extra.load(std::sync::atomic::Ordering::Relaxed))?;
+        writeln!(f, "{}+{} records out" , self.write_blk.load(std::sync::atomic::Ordering::Relaxed), self.write_extra.load(std::sync::atomic::Ordering::Relaxed) as u8)?;
+        match self.truncated.load(std::sync::atomic::Ordering::Relaxed) {
+            0 => {}
+            1 => writeln!(f, "1 truncated record")?,
+            n => writeln!(f, "{n} truncated records")?,
+        }
+        Ok(())
+    }
+
+    /// Returns the time since the transfer started
+    fn elapsed(&self) -> std::time::Duration {
+        self.started.read().unwrap().map(|t| t.elapsed()).unwrap_or_default()
+    }
+}
+
+/// Scales `v` to binary units, returns the scaled value and the unit.
+fn iec_units(v: f64) -> (f64, &'static str) {
+    match v {
+        b if b > 1024f64.powi(4) => ( b/1024f64.powi(4), "TiB"),
+        b if b > 1024f64.powi(3) => ( b/1024f64.powi(3), "GiB"),
+        b if b > 1024f64.powi(2) => ( b/1024f64.powi(2), "MiB"),
+        b if b > 1024f64 => ( b/1024f64, "KiB"),
+        b => (b, "B"),
     }
 }
 
 impl Display for GlobalState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{}+{} records in", self.read_blk.load(std::sync::atomic::Ordering::Relaxed), self.read_extra.load(std::sync::atomic::Ordering::Relaxed) as u8)?;
-        writeln!(f, "{}+{} records out" , self.write_blk.load(std::sync::atomic::Ordering::Relaxed), self.write_extra.load(std::sync::atomic::Ordering::Relaxed) as u8)?;
+        self.write_records(f)?;
         let bytes = self.bytes_written.load(std::sync::atomic::Ordering::Relaxed);
 
         if let Some((pow, unit)) = match bytes as f64 {
@@ -112,53 +289,101 @@ impl Display for GlobalState {
 
 
 
-        if let Ok(d) = self.started.read().unwrap().unwrap().elapsed() {
-            let mins = d.as_secs_f64() / 60f64;
-            let hrs = d.as_secs_f64() / 60f64.powi(2);
-            if hrs > 1.0 {
-                write!(f, ", {hrs:.3} h")?;
-            } else if mins > 1.0 {
-                write!(f, ", {mins:.3} m")?;
-            } else {
-                write!(f, ", {:.3} s", d.as_secs_f64())?;
-            }
+        let d = self.elapsed();
+        let mins = d.as_secs_f64() / 60f64;
+        let hrs = d.as_secs_f64() / 60f64.powi(2);
+        if hrs > 1.0 {
+            write!(f, ", {hrs:.3} h")?;
+        } else if mins > 1.0 {
+            write!(f, ", {mins:.3} m")?;
+        } else {
+            write!(f, ", {:.3} s", d.as_secs_f64())?;
+        }
 
-            let bps = self.bytes_written.load(std::sync::atomic::Ordering::Relaxed) as f64 / d.as_secs_f64();
-            let (bps, unit) = match bps {
-                b if b > 1024f64.powi(4) => ( b/1024f64.powi(4), "TiB/s"),
-                b if b > 1024f64.powi(3) => ( b/1024f64.powi(3), "GiB/s"),
-                b if b > 1024f64.powi(2) => ( b/1024f64.powi(2), "MiB/s"),
-                b => (b/1024f64, "KiB/s"),
-            };
+        let bps = self.bytes_written.load(std::sync::atomic::Ordering::Relaxed) as f64 / d.as_secs_f64();
+        let (bps, unit) = iec_units(bps);
 
-            write!(f, ", {bps:.2}{unit}")?;
+        write!(f, ", {bps:.2}{unit}/s")?;
+        writeln!(f)?;
+
+        let skipped = self.skipped.load(std::sync::atomic::Ordering::Relaxed);
+        if skipped > 0 {
+            writeln!(f, "{skipped} bytes of input skipped")?;
+        }
+        let sync = self.sync_time.load(std::sync::atomic::Ordering::Relaxed);
+        if sync > 0 {
+            writeln!(f, "{:.3} s spent flushing output", std::time::Duration::from_nanos(sync).as_secs_f64())?;
         }
-        writeln!(f,"")
+        hash::write_summary(f)
     }
 }
 
 impl Options {
+    /// Returns the input offset given by `skip=` in bytes
+    fn skip_bytes(&self) -> Option<u64> {
+        self.i_skip.map(|s| if self.i_flag.skip_bytes { s as u64 } else { s as u64 * self.i_bs as u64 })
+    }
+
+    /// Returns the output offset given by `seek=` in bytes
+    fn seek_bytes(&self) -> Option<u64> {
+        self.o_skip.map(|s| if self.o_flag.seek_bytes { s as u64 } else { s as u64 * self.o_bs as u64 })
+    }
+
+    /// Returns the amount of input given by `count=` in bytes
+    fn count_bytes(&self) -> Option<u64> {
+        self.count.map(|c| if self.i_flag.count_bytes { c as u64 } else { c as u64 * self.i_bs as u64 })
+    }
+
     const BRIEF: &'static str = "";
     fn new() -> Self {
+        Self::from_args(env::args())
+    }
+
+    /// Parses `args`, starting with the program name, exiting with a message if they are invalid.
+    fn from_args(args: impl IntoIterator<Item = String>) -> Self {
         use getopts::{HasArg, Occur};
         let mut opts = getopts::Options::new();
         // gnu `dd` options
         opts.opt("", "if","read from FILE instead of stdin","FILE",HasArg::Yes,Occur::Optional);
         opts.opt("", "of","write to FILE instead of stdout", "FILE", HasArg::Yes,Occur::Optional);
-        opts.opt("", "count","copy only N input blocks", "N", HasArg::Yes, Occur::Optional);
+        opts.opt("", "count","copy only N input blocks (or bytes with iflag=count_bytes)", "N", HasArg::Yes, Occur::Optional);
         opts.opt("", "bs","read and write up to BYTES bytes at a time (default: 512); overrides ibs and obs", "BYTES", HasArg::Yes, Occur::Optional);
         opts.opt("", "ibs","read up to BYTES bytes at a time (default: 512)","BYTES",HasArg::Yes,Occur::Optional);
         opts.opt("", "obs", "write BYTES bytes at a time (default: 512)", "BYTES", HasArg::Yes, Occur::Optional);
-        opts.opt("", "seek","skip N obs-sized output blocks. Note: oseek will not work", "N",HasArg::Yes, Occur::Optional);
-        opts.opt("", "skip", "skip N ibs-sized input blocks. Nose iseek does not work", "N", HasArg::Yes, Occur::Optional);
+        opts.opt("", "seek","skip N obs-sized output blocks (or bytes with oflag=seek_bytes)", "N",HasArg::Yes, Occur::Optional);
+        opts.opt("", "oseek","same as seek", "N",HasArg::Yes, Occur::Optional);
+        opts.opt("", "skip", "skip N ibs-sized input blocks (or bytes with iflag=skip_bytes)", "N", HasArg::Yes, Occur::Optional);
+        opts.opt("", "iseek","same as skip", "N",HasArg::Yes, Occur::Optional);
         opts.opt("", "status", "The LEVEL of information to print to stderr; 'none' suppresses everything but error messages, 'noxfer' suppresses the final transfer statistics, 'progress' shows periodic transfer statistics","LEVEL", HasArg::Yes, Occur::Optional);
+        opts.opt("", "conv", "convert the file as per the comma separated symbol list; 'ascii', 'ebcdic' and 'ibm' translate the character set, 'lcase' and 'ucase' change the case, 'swab' swaps every pair of bytes, 'block' and 'unblock' convert between newline terminated and cbs-sized records, 'fsync' and 'fdatasync' flush the output before finishing", "CONVS", HasArg::Yes, Occur::Multi);
+        opts.opt("", "cbs", "convert BYTES bytes at a time for 'block' and 'unblock'", "BYTES", HasArg::Yes, Occur::Optional);
+        opts.opt("", "iflag", "read as per the comma separated symbol list; 'fullblock' accumulates full blocks of input, 'skip_bytes' and 'count_bytes' treat skip and count as bytes, 'direct' bypasses the page cache, 'nocache' drops read data from the page cache, 'noatime' does not update the access time, 'nonblock' uses non-blocking IO", "FLAGS", HasArg::Yes, Occur::Multi);
+        opts.opt("", "oflag", "write as per the comma separated symbol list; 'sync' and 'dsync' open the output with O_SYNC and O_DSYNC, 'seek_bytes' treats seek as bytes, 'direct' bypasses the page cache, 'nocache' drops written data from the page cache, 'append' appends to the output, 'nonblock' uses non-blocking IO", "FLAGS", HasArg::Yes, Occur::Multi);
         opts.opt("","no-cfg", "Disables parsing the default config", "", HasArg::No, Occur::Optional);
 
         // disk destroyer options
         opts.opt("","cfg", "points to the config file to b used","PATH", HasArg::Yes,Occur::Multi);
         opts.opt("","help", "Prints a useful help message","",HasArg::No,Occur::Optional);
-
-        let matches = match opts.parse(env::args()) {
+        opts.opt("","stats-format", "FORMAT of the transfer statistics; 'human' (default), 'json' which prints one JSON object per line or 'gnu' which matches the output of GNU dd","FORMAT", HasArg::Yes,Occur::Optional);
+        opts.opt("","stats-fd", "write transfer statistics to file descriptor N instead of stderr","N", HasArg::Yes,Occur::Optional);
+        opts.opt("","on-interrupt", "what to do with data already read when interrupted by SIGINT, SIGTERM or SIGHUP; 'drain' (default) writes it, 'discard' drops it","POLICY", HasArg::Yes,Occur::Optional);
+        opts.opt("","buffer-size", "use up to BYTES for blocks waiting to be written (default: 16M)","BYTES", HasArg::Yes,Occur::Optional);
+        opts.opt("","queue-depth", "keep up to N blocks in flight with the io_uring backend (default: 32)","N", HasArg::Yes,Occur::Optional);
+        opts.opt("","jobs", "copy with N threads at once when the input and output are seekable (default: 1)","N", HasArg::Yes,Occur::Optional);
+        opts.opt("","size-hint", "expect BYTES of input when its size can not be determined, eg. when reading from a pipe","BYTES", HasArg::Yes,Occur::Optional);
+        opts.opt("","checkpoint", "periodically flush the output and record how far the transfer got in FILE, which is removed once it completes","FILE", HasArg::Yes,Occur::Optional);
+        opts.opt("","rate-limit", "copy at most BYTES per second, which can be changed while copying through --control","BYTES", HasArg::Yes,Occur::Optional);
+        opts.opt("","control", "accept commands on a unix socket at PATH; 'rate-limit BYTES' changes the limit, 'rate-limit off' removes it","PATH", HasArg::Yes,Occur::Optional);
+        opts.opt("","stall-timeout", "give up if no data is read or written for DURATION, or flushing the output takes 10 times as long, eg. '60s', '5m' or '500ms'","DURATION", HasArg::Yes,Occur::Optional);
+        opts.opt("","ionice", "use the I/O scheduling CLASS, 'realtime', 'best-effort' or 'idle', optionally followed by a level from 0 (highest) to 7 eg. 'best-effort:7'","CLASS[:LEVEL]", HasArg::Yes,Occur::Optional);
+        opts.opt("","nice", "run with the nice value N, from -20 (highest priority) to 19","N", HasArg::Yes,Occur::Optional);
+        opts.opt("","cpu-affinity", "only run on the comma separated CPUs or ranges in LIST eg. '0-3,6'","LIST", HasArg::Yes,Occur::Optional);
+        opts.opt("","verify", "once finished read the output back and compare it with the input, or with hashes recorded while copying if the input can not be read again","", HasArg::No,Occur::Optional);
+        opts.opt("","hash", "hash the input as it is read and the output as it is written with the comma separated ALGORITHMS, 'sha256', 'blake3', 'crc32' or 'md5'","ALGORITHMS", HasArg::Yes,Occur::Optional);
+        opts.opt("","hash-file", "write the digests to FILE in the format of sha256sum or md5sum, or of their --tag format for both; other digests are only shown in the summary; implies --hash sha256 if not given","FILE", HasArg::Yes,Occur::Optional);
+        opts.opt("","resume", "continue an interrupted transfer from the checkpoint in FILE, which keeps being updated unless --checkpoint is given; can not be used with --hash or --verify","FILE", HasArg::Yes,Occur::Optional);
+
+        let matches = match opts.parse(args) {
             Ok(m) => m,
             Err(e) => {
                 eprintln!("Failed to parse cmdline: {e:?}");
@@ -180,10 +405,44 @@ impl Options {
         let mut i_bs = None;
         let mut o_bs = None;
         let mut bs_spec = 0; // 0 for not configured yet. 1 for legacy opt. 2 for long opt.
+        let mut conv = Vec::new();
+        let mut cbs = None;
+        let mut i_flag = Vec::new();
+        let mut o_flag = Vec::new();
 
         // todo prepend '--' and re-parse these to remove this section
         for arg in matches.free.iter().skip(1) {
-            if arg.starts_with("if") {
+            // iflag and oflag must be checked before "if" and "of"
+            if arg.starts_with("iflag") {
+                i_flag.push(arg.split("=").nth(1).unwrap_or_else(|| {
+                    eprintln!("Expected iflag=[FLAGS], found: {arg}");
+                    opts.usage(Self::BRIEF);
+                    std::process::exit(2);
+                }).to_string())
+            } else if arg.starts_with("oflag") {
+                o_flag.push(arg.split("=").nth(1).unwrap_or_else(|| {
+                    eprintln!("Expected oflag=[FLAGS], found: {arg}");
+                    opts.usage(Self::BRIEF);
+                    std::process::exit(2);
+                }).to_string())
+            } else if arg.starts_with("conv") {
+                conv.push(arg.split("=").nth(1).unwrap_or_else(|| {
+                    eprintln!("Expected conv=[CONVS], found: {arg}");
+                    opts.usage(Self::BRIEF);
+                    std::process::exit(2);
+                }).to_string())
+            } else if arg.starts_with("cbs") {
+                if cbs.is_none() {
+                    cbs = Some(arg.split("=").nth(1).unwrap_or_else(|| {
+                        eprintln!("Expected cbs=[INT], found: {arg}");
+                        opts.usage(Self::BRIEF);
+                        std::process::exit(2);
+                    }).to_string())
+                } else {
+                    eprintln!("Multiple instances of \"cbs\" expected one");
+                    std::process::exit(3);
+                }
+            } else if arg.starts_with("if") {
                 if i_f.is_none() {
                     i_f = Some(arg.split("=").nth(1).unwrap_or_else(|| {
                         eprintln!("Expected if=[INT], found: {arg}");
@@ -260,26 +519,26 @@ impl Options {
                         }
                     }
                 }
-            } else if arg.starts_with("seek") {
-                if i_skip.is_none() {
-                    i_skip = Some(arg.split("=").nth(1).unwrap_or_else(|| {
+            } else if arg.starts_with("seek") || arg.starts_with("oseek") {
+                if o_skip.is_none() {
+                    o_skip = Some(arg.split("=").nth(1).unwrap_or_else(|| {
                         eprintln!("Expected seek=[INT], found: {arg}");
                         opts.usage(Self::BRIEF);
                         std::process::exit(2);
                     }).to_string())
                 } else {
-                    eprintln!("Multiple instances of \"seek\" expected one");
+                    eprintln!("Multiple instances of \"seek\" or \"oseek\" expected one");
                     std::process::exit(3);
                 }
-            } else if arg.starts_with("skip") {
-                if o_skip.is_none() {
-                    o_skip = Some(arg.split("=").nth(1).unwrap_or_else(|| {
+            } else if arg.starts_with("skip") || arg.starts_with("iseek") {
+                if i_skip.is_none() {
+                    i_skip = Some(arg.split("=").nth(1).unwrap_or_else(|| {
                         eprintln!("Expected skip=[INT], found: {arg}");
                         opts.usage(Self::BRIEF);
                         std::process::exit(2);
                     }).to_string())
                 } else {
-                    eprintln!("Multiple instances of \"skip\" expected one");
+                    eprintln!("Multiple instances of \"skip\" or \"iseek\" expected one");
                     std::process::exit(3);
                 }
             } else if arg.starts_with("status") {
@@ -297,7 +556,6 @@ impl Options {
         }
 
         if let Ok(Some(o)) = matches.opt_get("if") {
-            eprintln!("set i_f to {o:?}");
             i_f = Some(o); // most of these require args. If they require one but dont have one opts.parse will return err
         }
         if let Ok(Some(o)) = matches.opt_get("of") {
@@ -313,20 +571,40 @@ impl Options {
             if let Ok(Some(o)) = matches.opt_get("ibs") {
                 i_bs = Some(o) // most of these require args. If they require one but dont have one opts.parse will return err
             }
-            if let Ok(Some(o)) = matches.opt_get("of") {
+            if let Ok(Some(o)) = matches.opt_get("obs") {
                 o_bs = Some(o) // most of these require args. If they require one but dont have one opts.parse will return err
             }
         }
         if let Ok(Some(o)) = matches.opt_get("seek") {
             o_skip = Some(o) // most of these require args. If they require one but dont have one opts.parse will return err
+        } else if let Ok(Some(o)) = matches.opt_get("oseek") {
+            o_skip = Some(o)
         }
         if let Ok(Some(o)) = matches.opt_get("skip") {
             i_skip = Some(o) // most of these require args. If they require one but dont have one opts.parse will return err
+        } else if let Ok(Some(o)) = matches.opt_get("iseek") {
+            i_skip = Some(o)
         }
         if let Ok(Some(o)) = matches.opt_get("status") {
             status = Some(o) // most of these require args. If they require one but dont have one opts.parse will return err
         }
 
+        if let Ok(Some(o)) = matches.opt_get("cbs") {
+            cbs = Some(o)
+        }
+        conv.extend(matches.opt_strs("conv"));
+        i_flag.extend(matches.opt_strs("iflag"));
+        o_flag.extend(matches.opt_strs("oflag"));
+
+        let conv = flags::Conv::parse(&conv).unwrap_or_else(|e| {
+            eprintln!("Invalid conversion: {e:?}");
+            std::process::exit(3);
+        });
+        if let Some((a,b)) = conv.conflict() {
+            eprintln!("Conversions '{a}' and '{b}' are mutually exclusive");
+            std::process::exit(3);
+        }
+
         let mut cfg = config::ParsedCfg::new();
 
         // load default config if allowed
@@ -340,28 +618,74 @@ impl Options {
             cfg.load(i.into())
         }
 
-        Self {
+        let mut o = Self {
             o_f: o_f.map(|s| io::Target::Path(PathBuf::from(s))).unwrap_or(io::Target::StdOut),
             i_f: i_f.map(|s| io::Target::Path(PathBuf::from(s))).unwrap_or(io::Target::StdIn),
-            i_bs: i_bs.map(|s| Self::parse_units(&*s)).unwrap_or(512),
-            o_bs: o_bs.map(|s| Self::parse_units(&*s)).unwrap_or(512),
-            count: count.map(|s| s.parse().unwrap_or_else( |_| {
-                    eprintln!("Failed to parse {s}\nExpected integer");
-                    std::process::exit(3); })
-            ),
-            o_skip: o_skip.map(|s| s.parse().unwrap_or_else( |_| {
-                eprintln!("Failed to parse {s}\nExpected integer");
-                std::process::exit(3); })
-            ),
-            i_skip: i_skip.map(|s| s.parse().unwrap_or_else( |_| {
-                eprintln!("Failed to parse {s}\nExpected integer");
-                std::process::exit(3); })
-            ),
-            status: Status::try_from(&*status.unwrap_or("none".to_string())).unwrap_or_else(|_| {
+            i_bs: i_bs.map(|s| Self::parse_units(&s)).unwrap_or(512),
+            o_bs: o_bs.map(|s| Self::parse_units(&s)).unwrap_or(512),
+            count: count.map(|s| Self::parse_units(&s)),
+            o_skip: o_skip.map(|s| Self::parse_units(&s)),
+            i_skip: i_skip.map(|s| Self::parse_units(&s)),
+            stats_format: matches.opt_str("stats-format").map(|s| stats::Format::try_from(&*s).unwrap_or_else(|_| {
+                eprintln!("Failed to parse argument for 'stats-format'\nExpected 'human', 'json' or 'gnu'");
+                std::process::exit(3); })).unwrap_or(stats::Format::Human),
+            stats_fd: matches.opt_str("stats-fd").map(|s| s.parse().unwrap_or_else(|_| {
+                eprintln!("Failed to parse {s}\nExpected file descriptor");
+                std::process::exit(3); })),
+            on_interrupt: matches.opt_str("on-interrupt").map(|s| signal::Policy::try_from(&*s).unwrap_or_else(|_| {
+                eprintln!("Failed to parse argument for 'on-interrupt'\nExpected 'drain' or 'discard'");
+                std::process::exit(3); })).unwrap_or(signal::Policy::Drain),
+            buffer_size: matches.opt_str("buffer-size").map(|s| Self::parse_units(&s)).unwrap_or(16 * 1024usize.pow(2)),
+            queue_depth: matches.opt_str("queue-depth").map(|s| Self::parse_units(&s)).unwrap_or(32),
+            jobs: matches.opt_str("jobs").map(|s| Self::parse_units(&s)).unwrap_or(1),
+            size_hint: matches.opt_str("size-hint").map(|s| Self::parse_units(&s) as u64),
+            checkpoint: matches.opt_str("checkpoint").or(matches.opt_str("resume")).map(PathBuf::from),
+            resume: matches.opt_str("resume").map(|p| checkpoint::Point::load(p.as_ref())),
+            rate_limit: matches.opt_str("rate-limit").map(|s| Self::parse_units(&s) as u64),
+            control: matches.opt_str("control").map(PathBuf::from),
+            stall_timeout: matches.opt_str("stall-timeout").map(|s| Self::parse_duration(&s)),
+            verify: matches.opt_present("verify"),
+            hash: match (matches.opt_str("hash"), matches.opt_present("hash-file")) {
+                (Some(s), _) => hash::Algorithm::parse_list(&s).unwrap_or_else(|e| {
+                    eprintln!("Unknown hash algorithm: {e:?}\nExpected 'sha256', 'blake3', 'crc32' or 'md5'");
+                    std::process::exit(3); }),
+                (None, true) => vec![hash::Algorithm::Sha256],
+                (None, false) => Vec::new(),
+            },
+            hash_file: matches.opt_str("hash-file").map(PathBuf::from),
+            sched: cfg.sched().or(sched::Sched {
+                ionice: matches.opt_str("ionice").map(|s| sched::IoPrio::try_from(&*s).unwrap_or_else(|_| {
+                    eprintln!("Failed to parse argument for 'ionice'\nExpected 'realtime', 'best-effort' or 'idle' and maybe a level from 0 to 7 eg. 'idle' or 'be:7'");
+                    std::process::exit(3); })),
+                nice: matches.opt_str("nice").map(|s| s.parse().unwrap_or_else(|_| {
+                    eprintln!("Failed to parse {s}\nExpected a nice value from -20 to 19");
+                    std::process::exit(3); })),
+                cpu_affinity: matches.opt_str("cpu-affinity").map(|s| sched::parse_cpu_list(&s).unwrap_or_else(|_| {
+                    eprintln!("Failed to parse {s}\nExpected a list of CPUs eg. '0-3,6'");
+                    std::process::exit(3); })),
+            }),
+            status: status.map(|s| Status::try_from(&*s).unwrap_or_else(|_| {
                 eprintln!("Failed to parse argument for 'status'\nExpected 'none', 'noxfer' or 'progress'");
+                std::process::exit(3); })).unwrap_or(Status::Default),
+            conv,
+            cbs: cbs.map(|s| Self::parse_units(&s)),
+            i_flag: flags::IFlag::parse(&i_flag).unwrap_or_else(|e| {
+                eprintln!("Invalid input flag: {e:?}");
+                std::process::exit(3); }),
+            o_flag: flags::OFlag::parse(&o_flag).unwrap_or_else(|e| {
+                eprintln!("Invalid output flag: {e:?}");
                 std::process::exit(3); }),
             cfg,
+        };
+        let unchecked: Vec<_> = o.hash.iter().filter(|a| !a.checkable()).map(|a| a.name()).collect();
+        if o.hash_file.is_some() && !unchecked.is_empty() {
+            let what = if unchecked.len() == o.hash.len() { "Not writing --hash-file".to_string() } else { format!("Leaving {} out of --hash-file", unchecked.join(", ")) };
+            eprintln!("{what}, only sha256 and md5 digests can be checked by sha256sum, md5sum or cksum");
+        }
+        if let Some(p) = o.resume.clone() {
+            checkpoint::resume(&mut o, &p);
         }
+        o
     }
 
     fn units_map() -> std::collections::HashMap<&'static str,usize> {
@@ -381,32 +705,74 @@ impl Options {
     }
 
     fn parse_units(src: &str) -> usize {
+        Self::try_parse_units(src).unwrap_or_else(|| {
+            eprintln!("Failed to parse {src}\nExpected integer and maybe a following unit eg. '64K'");
+            std::process::exit(3);
+        })
+    }
+
+    /// Parses a duration such as '60s', '5m', '1h' or '500ms', plain numbers are seconds.
+    fn parse_duration(src: &str) -> std::time::Duration {
+        let (num, scale) = [("ms", 0.001), ("s", 1.0), ("m", 60.0), ("h", 3600.0)].into_iter()
+            .find_map(|(unit, scale)| src.strip_suffix(unit).map(|n| (n, scale)))
+            .unwrap_or((src, 1.0));
+        match num.parse::<f64>() {
+            Ok(n) if n > 0.0 && n.is_finite() => std::time::Duration::from_secs_f64(n * scale),
+            _ => {
+                eprintln!("Failed to parse {src}\nExpected a positive number and maybe a following unit eg. '60s'");
+                std::process::exit(3);
+            }
+        }
+    }
+
+    /// Parses an integer with an optional unit like [Self::parse_units], without exiting on failure.
+    fn try_parse_units(src: &str) -> Option<usize> {
         let mut src = src.to_string();
         let u = Self::units_map();
         for (k,v) in u {
             if src.ends_with(k) {
                 src.truncate(src.len() - k.len());
-                let val: usize = src.parse().unwrap_or_else(|_| {
-                    eprintln!("Failed to parse {src}\nExpected integer and maybe a following unit eg. '64K'");
-                    std::process::exit(3);
-                });
-                return val * v;
+                return src.parse::<usize>().ok().map(|val| val * v);
             }
         }
-        return src.parse().unwrap_or_else(|_| {
-            eprintln!("Failed to parse {src}\nExpected integer and maybe a following unit eg. '64K'");
-            std::process::exit(3);
-        })
+        src.parse().ok()
     }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
 enum Status {
-    Default,
+    /// Only errors are printed
+    None,
+    /// The final transfer statistics are not printed
     NoXFer,
+    Default,
+    /// Transfer statistics are also printed periodically
     Progress,
 }
 
+/// Why the transfer ended, reported in machine readable statistics.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+enum ExitReason {
+    Complete,
+    Error,
+    Interrupted,
+    /// Stopped by `--stall-timeout`
+    Stalled,
+}
+
+impl ExitReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ExitReason::Complete => "complete",
+            ExitReason::Error => "error",
+            ExitReason::Interrupted => "interrupted",
+            ExitReason::Stalled => "stalled",
+        }
+    }
+}
+
 impl TryFrom<&str> for Status {
     type Error = ();
 
@@ -414,80 +780,179 @@ impl TryFrom<&str> for Status {
         let mut s = value.to_string();
         s = s.to_lowercase();
         match &*s {
-            s if s == "none" => Ok(Self::Default),
-            s if s == "noxfer" => Ok(Self::NoXFer),
-            s if s == "progress" => Ok(Self::Progress),
+            "none" => Ok(Self::None),
+            "noxfer" => Ok(Self::NoXFer),
+            "progress" => Ok(Self::Progress),
             _ => Err(()),
         }
     }
 }
 struct IoQueue {
-    tx: std::sync::mpsc::Sender<Box<[u8]>>,
-    pending: Vec<u8>,
+    tx: std::sync::mpsc::SyncSender<buffer::Buffer>,
+    /// Blocks waiting to be sent, a whole number of them except at the end of the input
+    pending: buffer::Buffer,
+    /// The partial block kept back by [Self::flush]
+    tail: Vec<u8>,
+    pool: buffer::Pool,
     bs: usize,
 }
 
 impl IoQueue {
-    fn new(obs: usize, tx: std::sync::mpsc::Sender<Box<[u8]>>) -> Self {
+    /// Bytes of small blocks gathered before they are handed to the writer together
+    const BATCH: usize = 64 * 1024;
+
+    /// Returns the size of the buffers used with `ibs` and `obs`, a whole number of output
+    /// blocks which also fits a whole input block.
+    fn capacity(ibs: usize, obs: usize) -> usize {
+        obs * ibs.max(Self::BATCH).div_ceil(obs)
+    }
 
-        let s = Self {
+    fn new(obs: usize, tx: std::sync::mpsc::SyncSender<buffer::Buffer>, mut pool: buffer::Pool) -> Self {
+        Self {
             tx,
-            pending: Vec::new(),
+            pending: pool.get(),
+            tail: Vec::new(),
+            pool,
             bs: obs,
-        };
-            s
+        }
     }
 
-    fn push(&mut self, mut buff: Vec<u8>) {
-        #[cfg(debug)]
-        eprintln!("buff:    {buff:x?}");
-        // normal branch, just send it.
-        if buff.len() == self.bs {
-            self.tx.send(buff.into_boxed_slice()).expect("Receiving thread closed channel");
-            STATE.queued.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+    /// Takes an empty buffer from the pool.
+    fn buffer(&mut self) -> buffer::Buffer {
+        self.pool.get()
+    }
+
+    fn send(&mut self, buff: buffer::Buffer) {
+        self.tx.send(buff).expect("Receiving thread closed channel");
+    }
+
+    /// Queues the data in `buff`, which is replaced by an empty buffer if it was sent as is.
+    fn push(&mut self, buff: &mut buffer::Buffer) {
+        // large blocks are sent as they are. Anything pending must be sent first to keep the order.
+        if buff.len() >= Self::BATCH && buff.len().is_multiple_of(self.bs) && self.pending.is_empty() {
+            // send before taking a new buffer, which may have to wait for this one to be written
+            let full = std::mem::replace(buff, buffer::Buffer::empty(0));
+            self.send(full);
+            *buff = self.pool.get();
             return
         }
 
-        {
-            #[cfg(debug)]
-            eprintln!("Partial");
-            self.pending.append(&mut buff);
-            let ch = self.pending.chunks_exact(self.bs);
-            let mut r = ch.remainder().to_vec();
+        #[cfg(debug)]
+        eprintln!("Partial");
+        self.push_slice(buff);
+    }
 
-            for i in ch {
+    /// Copies `buff` after the pending blocks, sending them once the buffer is full.
+    fn push_slice(&mut self, mut buff: &[u8]) {
+        #[cfg(debug)]
+        eprintln!("buff:    {buff:x?}");
+        while !buff.is_empty() {
+            let n = (self.pending.capacity() - self.pending.len()).min(buff.len());
+            self.pending.extend_from_slice(&buff[..n]);
+            buff = &buff[n..];
+
+            if self.pending.len() == self.pending.capacity() {
+                let full = std::mem::replace(&mut self.pending, buffer::Buffer::empty(0));
                 #[cfg(debug)]
-                eprintln!("Sending: {i:x?}");
-                STATE.queued.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                self.tx.send(i.to_vec().into_boxed_slice()).expect("Receiving thread closed channel");
+                eprintln!("Sending: {} bytes", full.len());
+                self.send(full);
+                self.pending = self.pool.get();
             }
+        }
+
+        #[cfg(debug)] {
+            eprintln!("Remain:  {} bytes", self.pending.len());
+            eprintln!("--------")
+        }
+    }
 
-            self.pending.truncate(0);
-            self.pending = r;
+    /// Returns whether at least one whole block is pending.
+    fn holds_block(&self) -> bool {
+        self.pending.len() >= self.bs
+    }
 
-            #[cfg(debug)] {
-                eprintln!("Remain:  {:x?}", self.pending);
-                eprintln!("--------")
-            }
+    /// Sends every whole block which is pending, for when the input is slow to arrive and
+    /// waiting for a full buffer would hold the output back.
+    fn flush(&mut self) {
+        let whole = self.pending.len() - self.pending.len() % self.bs;
+        if whole == 0 {
+            return
         }
+        self.tail.extend_from_slice(&self.pending[whole..]);
+        self.pending.truncate(whole);
+        let full = std::mem::replace(&mut self.pending, buffer::Buffer::empty(0));
+        self.send(full);
+        self.pending = self.pool.get();
+        self.pending.extend_from_slice(&self.tail);
+        self.tail.clear();
     }
 }
 
 impl Drop for IoQueue {
     fn drop(&mut self) {
-        if self.pending.len() > 0 {
-
-            self.tx.send(std::mem::replace(&mut self.pending,Vec::new()).into_boxed_slice()).expect("Receiving thread closed channel");
-            STATE.queued.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+        if !self.pending.is_empty() {
+            let last = std::mem::replace(&mut self.pending, buffer::Buffer::empty(0));
+            self.send(last);
         }
     }
 }
 
 #[track_caller]
 pub fn handle_err(e: std::io::Error, msg: &str, code: i32) -> ! {
-    eprintln!("{} for {msg}",e.to_string());
+    eprintln!("{} for {msg}",e);
 
     let c = std::panic::Location::caller();
     eprintln!("from {c}");
+    exit_on_err(code)
+}
+
+/// Exits with `code` after an error which has already been reported, printing the statistics if the transfer started.
+pub fn exit_on_err(code: i32) -> ! {
+    STATE.failed.store(true, std::sync::atomic::Ordering::Relaxed);
+    // errors may happen before the transfer starts
+    if STATE.started.read().is_ok_and(|s| s.is_some()) {
+        STATE.exit_reason.store(ExitReason::Error as u8, std::sync::atomic::Ordering::Relaxed);
+        print_stats();
+    }
     std::process::exit(code)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Options {
+        Options::from_args(["dd", "--no-cfg"].into_iter().chain(args.split_whitespace()).map(String::from))
+    }
+
+    #[test]
+    fn skip_in_blocks_or_bytes() {
+        assert_eq!(parse("skip=3 ibs=1K").skip_bytes(), Some(3072));
+        assert_eq!(parse("skip=3 ibs=1K iflag=skip_bytes").skip_bytes(), Some(3));
+        assert_eq!(parse("--skip 3 --iflag skip_bytes").skip_bytes(), Some(3));
+        assert_eq!(parse("bs=4K").skip_bytes(), None);
+    }
+
+    #[test]
+    fn seek_in_blocks_or_bytes() {
+        assert_eq!(parse("seek=2 obs=1K").seek_bytes(), Some(2048));
+        assert_eq!(parse("seek=2 obs=1K oflag=seek_bytes").seek_bytes(), Some(2));
+        // skip_bytes only applies to the input
+        assert_eq!(parse("seek=2 bs=1K iflag=skip_bytes").seek_bytes(), Some(2048));
+    }
+
+    #[test]
+    fn count_in_blocks_or_bytes() {
+        assert_eq!(parse("count=5 bs=1M").count_bytes(), Some(5 * 1024 * 1024));
+        assert_eq!(parse("count=5K bs=1M iflag=count_bytes").count_bytes(), Some(5 * 1024));
+    }
+
+    #[test]
+    fn iseek_and_oseek_aliases() {
+        assert_eq!(parse("iseek=2 bs=4K").skip_bytes(), Some(8192));
+        assert_eq!(parse("--iseek 2 --bs 4K").skip_bytes(), Some(8192));
+        assert_eq!(parse("iseek=7 iflag=skip_bytes").skip_bytes(), Some(7));
+        assert_eq!(parse("oseek=5 oflag=seek_bytes").seek_bytes(), Some(5));
+        assert_eq!(parse("--oseek 3 --obs 512").seek_bytes(), Some(1536));
+    }
+}
@@ -0,0 +1,193 @@
+//! Parallel copy for `--jobs`, where several threads copy separate chunks of the range with
+//! `pread`/`pwrite`.
+//!
+//! Workers only copy full blocks, the final partial block is copied by [copy] once they have
+//! finished so it is handled the same way on every run. With `iflag=nocache` or `oflag=nocache`
+//! each worker drops the cache behind the chunk it is copying.
+
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::buffer::Buffer;
+use crate::read_write::CacheDropper;
+
+/// Bytes copied by a worker before it takes the next chunk
+const CHUNK: usize = 16 * 1024 * 1024;
+
+fn position(mut f: &std::fs::File) -> u64 {
+    std::io::Seek::stream_position(&mut f).unwrap_or(0)
+}
+
+/// Returns why `--jobs` can not be used, if it can not.
+fn refusal(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> Option<&'static str> {
+    if crate::conv::Converter::new(&opts.conv, opts.cbs).is_ordered() {
+        Some("conversions which depend on the order of the input are active")
+    } else if opts.i_bs != opts.o_bs {
+        Some("ibs and obs differ")
+    } else if opts.o_flag.append {
+        Some("the output is opened for appending")
+    } else if opts.checkpoint.is_some() {
+        Some("checkpoints need the output to be written in order")
+    } else if !opts.hash.is_empty() {
+        Some("hashes need the data in order")
+    } else if !crate::io::seekable(input) || !crate::io::seekable(output) {
+        Some("the input and output must both be seekable")
+    } else if crate::io::size(input).is_none() {
+        Some("the size of the input is not known")
+    } else {
+        None
+    }
+}
+
+/// State shared by the workers.
+struct Job<'a> {
+    opts: &'a crate::Options,
+    input: &'a std::fs::File,
+    output: &'a std::fs::File,
+    i_base: u64,
+    o_base: u64,
+    /// Number of full blocks to copy
+    blocks: u64,
+    /// Next chunk to be taken by a worker
+    next: AtomicU64,
+}
+
+impl Job<'_> {
+    /// Returns what drops the cache of the input and output from the block at `index` on.
+    fn droppers(&self, index: u64) -> [(&std::fs::File, Option<CacheDropper>); 2] {
+        let offset = index * self.opts.i_bs as u64;
+        [
+            (self.input, self.opts.i_flag.nocache.then(|| CacheDropper::new(self.i_base + offset, false))),
+            (self.output, self.opts.o_flag.nocache.then(|| CacheDropper::new(self.o_base + offset, true))),
+        ]
+    }
+
+    /// Copies the block at `index`, returning its length.
+    fn block(&self, conv: &crate::conv::Converter, buff: &mut [u8], index: u64) -> usize {
+        let (opts, s) = (self.opts, &crate::STATE);
+        let offset = index * opts.i_bs as u64;
+
+        let busy = crate::watchdog::busy(crate::watchdog::Side::Read);
+        let n = crate::io::read_at(self.input, buff, self.i_base + offset)
+            .unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.i_f), 0x21));
+        drop(busy);
+        if n == 0 {
+            return 0;
+        }
+        s.bytes_read.fetch_add(n, Ordering::Relaxed);
+        if n < opts.i_bs {
+            s.read_extra.fetch_add(1, Ordering::Relaxed);
+        } else {
+            s.read_blk.fetch_add(1, Ordering::Relaxed);
+        }
+
+        conv.translate(&mut buff[..n]);
+        crate::rate::throttle(crate::rate::Side::Write, n);
+        let busy = crate::watchdog::busy(crate::watchdog::Side::Write);
+        self.output.write_all_at(&buff[..n], self.o_base + offset)
+            .unwrap_or_else(|e| crate::read_write::handle_write_err(e, opts, self.o_base + offset));
+        drop(busy);
+        s.bytes_written.fetch_add(n, Ordering::Relaxed);
+        if n < opts.o_bs {
+            s.write_extra.store(true, Ordering::Relaxed);
+        } else {
+            s.write_blk.fetch_add(1, Ordering::Relaxed);
+        }
+        n
+    }
+
+    fn work(&self) {
+        let conv = crate::conv::Converter::new(&self.opts.conv, self.opts.cbs);
+        let mut buff = Buffer::new(self.opts.i_bs);
+        let per_chunk = (CHUNK / self.opts.i_bs).max(1) as u64;
+
+        loop {
+            let first = self.next.fetch_add(1, Ordering::Relaxed) * per_chunk;
+            if first >= self.blocks {
+                return;
+            }
+            let mut droppers = self.droppers(first);
+            for index in first..(first + per_chunk).min(self.blocks) {
+                if crate::signal::poll() {
+                    break;
+                }
+                let n = self.block(&conv, &mut buff, index);
+                for (f, d) in &mut droppers {
+                    if let Some(d) = d {
+                        d.advance(f, n);
+                    }
+                }
+            }
+            for (f, d) in &mut droppers {
+                if let Some(d) = d {
+                    d.finish(f);
+                }
+            }
+            if crate::signal::pending().is_some() {
+                return;
+            }
+        }
+    }
+}
+
+/// Copies from `input` to `output` with `--jobs` threads.
+///
+/// Returns false without transferring anything if `--jobs` was not given or can not be used,
+/// the data should be copied in order instead.
+pub fn copy(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> bool {
+    if opts.jobs <= 1 {
+        return false;
+    }
+    if let Some(why) = refusal(opts, input, output) {
+        info!("Ignoring --jobs, {why}");
+        return false;
+    }
+
+    let bs = opts.i_bs;
+    let i_base = position(input);
+    let o_base = position(output);
+    let mut len = crate::io::size(input).unwrap_or(0).saturating_sub(i_base);
+    if let Some(c) = opts.count_bytes() {
+        len = len.min(c);
+    }
+
+    crate::read_write::check_direct(input, &opts.i_f, i_base, bs);
+    crate::read_write::check_direct(output, &opts.o_f, o_base, bs);
+
+    let job = Job {
+        opts,
+        input,
+        output,
+        i_base,
+        o_base,
+        blocks: len / bs as u64,
+        next: AtomicU64::new(0),
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..opts.jobs {
+            crate::signal::spawn_scoped(scope, || job.work())
+                .unwrap_or_else(|e| crate::handle_err(e, "starting a worker thread", 0x21));
+        }
+        job.work();
+    });
+
+    let tail = (len % bs as u64) as usize;
+    if tail > 0 && crate::signal::pending().is_none() {
+        for (f, target) in [(input, &opts.i_f), (output, &opts.o_f)] {
+            if crate::io::is_direct(f) && !tail.is_multiple_of(crate::io::logical_block_size(f)) {
+                crate::io::clear_direct(f).unwrap_or_else(|e| crate::handle_err(e, &format!("in file {target}"), 0x23));
+            }
+        }
+        let conv = crate::conv::Converter::new(&opts.conv, opts.cbs);
+        let mut buff = Buffer::new(tail);
+        let mut droppers = job.droppers(job.blocks);
+        let n = job.block(&conv, &mut buff, job.blocks);
+        for (f, d) in &mut droppers {
+            if let Some(d) = d {
+                d.advance(f, n);
+                d.finish(f);
+            }
+        }
+    }
+    true
+}
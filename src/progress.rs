@@ -0,0 +1,86 @@
+//! Periodic transfer statistics for `status=progress`.
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle to the thread redrawing the progress line.
+/// [Progress::finish] must be called before printing anything else to stderr.
+pub struct Progress {
+    stop: std::sync::mpsc::Sender<()>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Progress {
+    pub fn spawn() -> Self {
+        let (stop, rx) = std::sync::mpsc::channel();
+        let thread = crate::signal::spawn(move || {
+            let mut line_len = 0;
+            let mut last = (Instant::now(), 0);
+
+            // the sender is dropped by finish()
+            while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(INTERVAL) {
+                let bytes = crate::STATE.bytes_written.load(Ordering::Relaxed);
+                let now = Instant::now();
+                let current = (bytes - last.1) as f64 / now.duration_since(last.0).as_secs_f64();
+                last = (now, bytes);
+
+                if crate::stats::format() == crate::stats::Format::Json {
+                    crate::stats::emit(&crate::stats::json("progress"));
+                    continue;
+                }
+
+                let line = Self::line(bytes, current);
+                // pad with spaces to cover anything left from a longer line
+                crate::stats::emit(&format!("\r{line:line_len$}"));
+                line_len = line.len();
+            }
+
+            if line_len > 0 {
+                crate::stats::emit(&format!("\r{:line_len$}\r", ""));
+            }
+        });
+
+        Self {
+            stop,
+            thread,
+        }
+    }
+
+    fn line(bytes: usize, current: f64) -> String {
+        let elapsed = crate::STATE.elapsed();
+        let (b, b_unit) = crate::iec_units(bytes as f64);
+        let mut line = format!("{bytes} bytes ({b:.1}{b_unit}) copied, {:.0} s", elapsed.as_secs_f64());
+
+        let avg = bytes as f64 / elapsed.as_secs_f64();
+        let (avg, avg_unit) = crate::iec_units(avg);
+        let (cur, cur_unit) = crate::iec_units(current);
+        line += &format!(", {cur:.2}{cur_unit}/s, {avg:.2}{avg_unit}/s avg");
+        if let Some(limit) = crate::rate::limit() {
+            let (l, l_unit) = crate::iec_units(limit as f64);
+            line += &format!(" (limit {l:.2}{l_unit}/s)");
+        }
+
+        let expected = crate::STATE.expected.load(Ordering::Relaxed);
+        if expected > 0 {
+            let read = crate::STATE.bytes_read.load(Ordering::Relaxed) as u64;
+            let pct = (read as f64 / expected as f64 * 100.0).min(100.0);
+            line += &format!(", {pct:.1}%");
+
+            let read_rate = read as f64 / elapsed.as_secs_f64();
+            if read_rate > 0.0 {
+                let eta = expected.saturating_sub(read) as f64 / read_rate;
+                let eta = eta as u64;
+                line += &format!(" ETA {}:{:02}:{:02}", eta / 3600, eta / 60 % 60, eta % 60);
+            }
+        }
+        line
+    }
+
+    /// Stops the thread and clears the progress line.
+    pub fn finish(self) {
+        drop(self.stop);
+        drop(self.thread.join());
+    }
+}
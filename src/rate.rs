@@ -0,0 +1,82 @@
+//! Bandwidth limiting for `--rate-limit`.
+//!
+//! Reads and writes each have a token bucket shared by every backend. Each block takes its
+//! length in tokens and waits for the bucket to refill when it runs dry. Limiting the reads too
+//! keeps the input from getting further ahead of the output than the buffers allow, and with a
+//! bucket for each side limiting both does not halve the rate. Backends which read and write
+//! in the same call only take from one. The limit is kept in an atomic so it can be changed
+//! while copying, see [crate::control].
+//!
+//! The limit still applies while the buffered data is drained after a terminating signal, a
+//! second signal ends the process. Only `--on-interrupt discard` stops waiting.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bytes per second, 0 if unlimited
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+/// Indexed by [Side]
+static BUCKETS: [Mutex<Option<Bucket>>; 2] = [Mutex::new(None), Mutex::new(None)];
+
+/// The side of the transfer a bucket limits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Side {
+    Read,
+    Write,
+}
+
+/// Tokens saved up while idle, in seconds at the current limit
+const BURST: Duration = Duration::from_millis(100);
+/// Longest sleep before checking for a new limit and whether the data is being discarded
+const STEP: Duration = Duration::from_millis(100);
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: u64) {
+        let now = Instant::now();
+        let burst = limit as f64 * BURST.as_secs_f64();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * limit as f64).min(burst);
+        self.last = now;
+    }
+}
+
+/// Sets the limit in bytes per second, 0 removes it.
+pub fn set(limit: u64) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Returns the limit in bytes per second, if there is one.
+pub fn limit() -> Option<u64> {
+    match LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        l => Some(l),
+    }
+}
+
+/// Waits until `len` more bytes may be read or written on `side` without exceeding the limit.
+/// Returns early if the transfer is stopped with `--on-interrupt discard`.
+pub fn throttle(side: Side, len: usize) {
+    let Some(limit) = limit() else {
+        return;
+    };
+    // a worker which panicked while holding the lock left a usable bucket
+    let mut guard = BUCKETS[side as usize].lock().unwrap_or_else(|e| e.into_inner());
+    let b = guard.get_or_insert_with(|| Bucket { tokens: 0.0, last: Instant::now() });
+    b.refill(limit);
+    b.tokens -= len as f64;
+
+    while b.tokens < 0.0 && !crate::signal::discarding() {
+        // the limit may be changed or removed while waiting
+        let Some(limit) = self::limit() else {
+            b.tokens = 0.0;
+            break;
+        };
+        std::thread::sleep(Duration::from_secs_f64(-b.tokens / limit as f64).min(STEP));
+        b.refill(limit);
+    }
+}
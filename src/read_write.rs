@@ -1,67 +1,379 @@
 use std::io::{Read, Seek, SeekFrom, Write};
+use crate::buffer::Buffer;
 use crate::io::IoMode;
 use crate::Options;
 
 
 
-fn new_buff(len: usize) -> Vec<u8> {
-    let mut  v = Vec::new();
-    v.resize(len,0);
-    v
+/// Checks that IO at `offset` with blocks of `bs` bytes satisfies the alignment required by `O_DIRECT`.
+/// If not direct IO is disabled for `f`.
+/// Returns whether `f` is still using direct IO.
+pub fn check_direct(f: &std::fs::File, target: &crate::io::Target, offset: u64, bs: usize) -> bool {
+    if !crate::io::is_direct(f) {
+        return false
+    }
+
+    let lbs = crate::io::logical_block_size(f);
+    if !offset.is_multiple_of(lbs as u64) || !bs.is_multiple_of(lbs) {
+        info!("Offset or block size for {target} is not a multiple of {lbs} bytes, continuing without direct IO");
+        crate::io::clear_direct(f).unwrap_or_else(|e| super::handle_err(e,&format!("in file {target}"),0x23));
+        return false
+    }
+    true
+}
+
+/// Drops pages from the page cache once they have been read or written, for `iflag=nocache` and `oflag=nocache`.
+/// Dirty pages cannot be dropped so output data is synced first.
+pub struct CacheDropper {
+    /// Start of the range which has not been dropped yet
+    start: u64,
+    pos: u64,
+    sync: bool,
 }
 
-pub fn dd_read(opts: Options, tx: std::sync::mpsc::Sender<Box<[u8]>>) {
-    let mut f = opts.i_f.open(IoMode::Read,&opts);
-    let mut queue = super::IoQueue::new(opts.o_bs,tx);
+impl CacheDropper {
+    /// Number of bytes between each drop
+    const INTERVAL: u64 = 16 * 1024 * 1024;
 
-    if let Some(skip) = opts.i_skip {
-        f.seek(SeekFrom::Start(skip as u64 * opts.i_bs as u64)).unwrap_or_else(|e| super::handle_err(e,&format!("in file {:?}",opts.i_f),0x20));
+    pub fn new(offset: u64, sync: bool) -> Self {
+        Self {
+            start: offset,
+            pos: offset,
+            sync,
+        }
     }
 
-    let max_queued_len = 1; // todo change to x bytes
+    pub fn advance(&mut self, f: &std::fs::File, len: usize) {
+        self.pos += len as u64;
+        if self.pos - self.start >= Self::INTERVAL {
+            self.drop_cache(f);
+        }
+    }
 
-    let mut b = new_buff(opts.i_bs);
-    for _ in 0..opts.count.unwrap_or(usize::MAX) {
-        while super::STATE.queued.load(std::sync::atomic::Ordering::Relaxed) >= max_queued_len {
-            std::thread::sleep(std::time::Duration::from_millis(10)); //todo handle better
+    /// Drops everything up to the current position.
+    /// Errors are ignored, pipes and some devices have no page cache to drop.
+    pub fn drop_cache(&mut self, f: &std::fs::File) {
+        use std::os::fd::AsRawFd;
+        if self.sync {
+            let _busy = crate::watchdog::busy(crate::watchdog::Side::Flush);
+            let _ = f.sync_data();
         }
+        // SAFETY: posix_fadvise does not touch memory
+        unsafe { libc::posix_fadvise(f.as_raw_fd(), self.start as libc::off_t, (self.pos - self.start) as libc::off_t, libc::POSIX_FADV_DONTNEED) };
+        self.start = self.pos;
+    }
 
-        let r_len = f.read(&mut b).unwrap_or_else(|e| super::handle_err(e,&format!("in file {}",opts.i_f),0x21));
-        b.truncate(r_len);
+    /// Drops the range not dropped yet, if there is one. [Self::drop_cache] with nothing left
+    /// drops everything to the end of the file.
+    pub fn finish(&mut self, f: &std::fs::File) {
+        if self.pos > self.start {
+            self.drop_cache(f);
+        }
+    }
+}
 
-        queue.push(core::mem::replace(&mut b,new_buff(opts.i_bs)));
+/// Performs a single read, retrying if interrupted.
+fn read_some(f: &mut std::fs::File, buff: &mut [u8]) -> std::io::Result<usize> {
+    let _busy = crate::watchdog::busy(crate::watchdog::Side::Read);
+    loop {
+        match f.read(buff) {
+            // a terminating signal ends the input like EOF
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => if crate::signal::poll() {
+                return Ok(0);
+            },
+            r => return r,
+        }
+    }
+}
 
-        // incomplete read is exit condition
-        if r_len < opts.i_bs {
-            super::STATE.read_extra.store(true,std::sync::atomic::Ordering::Relaxed);
-            break
+/// Reads until `buff` is full or the end of the input is reached, used for `iflag=fullblock`.
+fn read_full(f: &mut std::fs::File, buff: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buff.len() {
+        match read_some(f, &mut buff[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Returns the number of bytes which will be read from `f`, or 0 if it is not known.
+/// `--size-hint` stands in for the size of inputs which can not be measured, like pipes.
+fn expected_len(f: &std::fs::File, opts: &Options) -> u64 {
+    let len = crate::io::size(f).or(opts.size_hint).map(|l| l.saturating_sub(opts.skip_bytes().unwrap_or(0)));
+
+    match (len, opts.count_bytes()) {
+        (Some(l), Some(c)) => l.min(c),
+        (Some(l), None) => l,
+        (None, Some(c)) => c,
+        (None, None) => 0,
+    }
+}
+
+/// Opens the input and moves past `skip`, by reading and discarding the data if it is not seekable.
+pub fn open_input(opts: &Options) -> std::fs::File {
+    let mut f = opts.i_f.open(IoMode::Read,opts);
+    if let Some(skip) = opts.skip_bytes() {
+        let skipped = match f.seek(SeekFrom::Start(skip)) {
+            Ok(_) => crate::io::size(&f).map_or(skip, |len| len.min(skip)),
+            Err(e) if e.raw_os_error() == Some(libc::ESPIPE) => discard(&mut f, skip, opts),
+            Err(e) => super::handle_err(e,&format!("in file {:?}",opts.i_f),0x20),
+        };
+        super::STATE.skipped.store(skipped, std::sync::atomic::Ordering::Relaxed);
+        if skipped < skip && crate::signal::pending().is_none() {
+            info!("Skipped only {skipped} of {skip} bytes, {} ended first", opts.i_f);
+        }
+    }
+    super::STATE.expected.store(expected_len(&f, opts), std::sync::atomic::Ordering::Relaxed);
+    f
+}
+
+/// Opens the output and seeks past `seek`.
+pub fn open_output(opts: &Options) -> std::fs::File {
+    let mut f = opts.o_f.open(IoMode::Write,opts);
+    if let Some(seek) = opts.seek_bytes() {
+        match f.seek(SeekFrom::Start(seek)) {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ESPIPE) => {
+                eprintln!("Can not seek {seek} bytes into {}, seek= needs a seekable output such as a file or block device", opts.o_f);
+                super::exit_on_err(0x20)
+            }
+            Err(e) => super::handle_err(e,&format!("in file {:?}",opts.o_f), 0x20),
         }
+    }
+    f
+}
 
-        super::STATE.read_blk.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+/// Reads and drops `len` bytes from a non-seekable input, returning how many bytes were
+/// skipped before the input ended.
+fn discard(f: &mut std::fs::File, len: u64, opts: &Options) -> u64 {
+    let mut b = Buffer::new(opts.i_bs);
+    let mut skipped = 0;
+    while skipped < len && !crate::signal::poll() {
+        let want = (len - skipped).min(opts.i_bs as u64) as usize;
+        match read_some(f, &mut b[..want]).unwrap_or_else(|e| super::handle_err(e,&format!("in file {}",opts.i_f),0x21)) {
+            0 => break,
+            n => skipped += n as u64,
+        }
     }
+    skipped
 }
 
+pub fn dd_read(opts: Options, mut f: std::fs::File, tx: std::sync::mpsc::SyncSender<Buffer>, pool: crate::buffer::Pool) {
+    let mut queue = super::IoQueue::new(opts.o_bs,tx,pool);
+    let mut conv = crate::conv::Converter::new(&opts.conv,opts.cbs);
 
+    let mut direct = check_direct(&f, &opts.i_f, opts.skip_bytes().unwrap_or(0), opts.i_bs);
+    let lbs = crate::io::logical_block_size(&f);
+    let mut nocache = opts.i_flag.nocache.then(|| CacheDropper::new(opts.skip_bytes().unwrap_or(0), false));
 
-pub fn dd_write(opts: Options, rx: std::sync::mpsc::Receiver<Box<[u8]>>) {
-    let mut f = opts.o_f.open(IoMode::Write,&opts);
-    if let Some(skip) = opts.o_skip {
-        f.seek(SeekFrom::Start(skip as u64 * opts.o_bs as u64)).unwrap_or_else(|e| super::handle_err(e,&format!("in file {:?}",opts.o_f), 0x20));
+    // like GNU dd `count=0` with nocache drops the whole file
+    if opts.count == Some(0) {
+        if let Some(c) = &mut nocache {
+            c.start = 0;
+            c.pos = f.metadata().map(|m| m.len()).unwrap_or(0);
+            c.drop_cache(&f);
+        }
     }
 
+    // with iflag=count_bytes the count is the number of bytes left to read, otherwise it is in blocks
+    let mut remaining = opts.count.unwrap_or(usize::MAX);
+    let slow = may_wait(&f);
 
-    while let Ok(blk) = rx.recv() {
-        super::STATE.queued.fetch_sub(1,std::sync::atomic::Ordering::Relaxed);
-        let len: usize = blk.len();
-        let rc = f.write(&*blk).unwrap_or_else(|e| super::handle_err(e,&format!("in file {:?}",opts.o_f), 0x21));
-        super::STATE.bytes_written.fetch_add(blk.len(), core::sync::atomic::Ordering::Relaxed);
+    let mut b = queue.buffer();
+    while remaining > 0 {
+        if crate::signal::poll() {
+            break
+        }
+        // hand over what has been gathered rather than hold it back while waiting for more input
+        if slow && queue.holds_block() && !wait_ready_for(&f, libc::POLLIN, 0) {
+            queue.flush();
+        }
 
-        //eprintln!("w: {:x?}",blk);
+        let want = if opts.i_flag.count_bytes { remaining.min(opts.i_bs) } else { opts.i_bs };
+        // the last read of count_bytes may not be aligned
+        if direct && !want.is_multiple_of(lbs) {
+            crate::io::clear_direct(&f).unwrap_or_else(|e| super::handle_err(e,&format!("in file {}",opts.i_f),0x23));
+            direct = false;
+        }
+        b.set_len(want);
+        let r_len = if opts.i_flag.fullblock {
+            read_full(&mut f, &mut b)
+        } else {
+            read_some(&mut f, &mut b)
+        }.unwrap_or_else(|e| super::handle_err(e,&format!("in file {}",opts.i_f),0x21));
 
-        if rc < opts.o_bs || len < opts.o_bs {
-            super::STATE.write_extra.store(true,std::sync::atomic::Ordering::Relaxed);
+        // only an empty read is the end of the input, pipes may return less than requested at any time
+        if r_len == 0 {
             break
         }
-        super::STATE.write_blk.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+
+        // limits how far the reader gets ahead of the writer, which is limited as well
+        crate::rate::throttle(crate::rate::Side::Read, r_len);
+        remaining -= if opts.i_flag.count_bytes { r_len } else { 1 };
+        super::STATE.bytes_read.fetch_add(r_len, std::sync::atomic::Ordering::Relaxed);
+        if let Some(c) = &mut nocache {
+            c.advance(&f, r_len);
+        }
+        b.truncate(r_len);
+        crate::hash::input(&b);
+        let n = conv.convert(&mut b);
+        b.truncate(n);
+        match conv.reblock(&b) {
+            Some(out) => queue.push_slice(out),
+            None => queue.push(&mut b),
+        }
+
+        if r_len < opts.i_bs {
+            super::STATE.read_extra.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+        } else {
+            super::STATE.read_blk.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    queue.push_slice(&conv.finish());
+    if let Some(c) = &mut nocache {
+        c.drop_cache(&f);
+    }
+}
+
+
+
+pub fn dd_write(opts: Options, mut f: std::fs::File, rx: std::sync::mpsc::Receiver<Buffer>, recycle: crate::buffer::Recycler, mut checkpoint: Option<crate::checkpoint::Checkpoint>) {
+    let mut direct = check_direct(&f, &opts.o_f, opts.seek_bytes().unwrap_or(0), opts.o_bs);
+    let lbs = crate::io::logical_block_size(&f);
+    let mut nocache = opts.o_flag.nocache.then(|| CacheDropper::new(f.stream_position().unwrap_or(0), true));
+    // only used to report where the output ran out of space
+    let mut offset = f.stream_position().unwrap_or(0);
+
+    // each buffer holds as many blocks as fit, every one is written separately
+    while let Ok(blocks) = rx.recv() {
+        for blk in blocks.chunks(opts.o_bs) {
+            // keep receiving so the reader is not blocked, but write nothing more
+            if opts.on_interrupt == crate::signal::Policy::Discard && crate::signal::pending().is_some() {
+                break
+            }
+            let len: usize = blk.len();
+
+            // the final block may be short
+            if direct && !len.is_multiple_of(lbs) {
+                crate::io::clear_direct(&f).unwrap_or_else(|e| super::handle_err(e,&format!("in file {}",opts.o_f),0x23));
+                direct = false;
+            }
+            crate::rate::throttle(crate::rate::Side::Write, len);
+            write_all(&mut f, blk, &opts, offset);
+            crate::verify::record(blk);
+            crate::hash::output(blk);
+            offset += len as u64;
+            if let Some(c) = &mut nocache {
+                c.advance(&f, len);
+            }
+
+            //eprintln!("w: {:x?}",blk);
+
+            // only the final block can be short
+            if len < opts.o_bs {
+                super::STATE.write_extra.store(true,std::sync::atomic::Ordering::Relaxed);
+            } else {
+                super::STATE.write_blk.fetch_add(1,std::sync::atomic::Ordering::Relaxed);
+            }
+            if let Some(c) = &mut checkpoint {
+                c.update(&f);
+            }
+        }
+        recycle.put(blocks);
+    }
+
+    if let Some(c) = &mut nocache {
+        c.drop_cache(&f);
+    }
+
+    sync_output(&opts, &f);
+    if let Some(c) = checkpoint {
+        c.finish(&f);
+    }
+}
+
+/// Writes all of `buff` to the output at `offset`, which may take several writes for pipes,
+/// sockets and non-blocking outputs.
+fn write_all(f: &mut std::fs::File, mut buff: &[u8], opts: &Options, mut offset: u64) {
+    let len = buff.len();
+    let _busy = crate::watchdog::busy(crate::watchdog::Side::Write);
+    // a block cut short by an error still counts as a partial record
+    let fail = |e: std::io::Error, offset: u64, left: usize| -> ! {
+        if left < len {
+            super::STATE.write_extra.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+        handle_write_err(e, opts, offset)
+    };
+
+    while !buff.is_empty() {
+        match f.write(buff) {
+            Ok(0) => fail(std::io::ErrorKind::WriteZero.into(), offset, buff.len()),
+            Ok(n) => {
+                buff = &buff[n..];
+                offset += n as u64;
+                super::STATE.bytes_written.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => wait_ready(f, libc::POLLOUT),
+            Err(e) => fail(e, offset, buff.len()),
+        }
+    }
+}
+
+/// Blocks until a non-blocking file is ready for `events`, `POLLIN` or `POLLOUT`.
+pub fn wait_ready(f: &std::fs::File, events: libc::c_short) {
+    wait_ready_for(f, events, -1);
+}
+
+/// Waits up to `timeout` milliseconds for `events` on `f`, returning whether any happened.
+fn wait_ready_for(f: &std::fs::File, events: libc::c_short, timeout: libc::c_int) -> bool {
+    use std::os::fd::AsRawFd;
+    let mut fd = libc::pollfd { fd: f.as_raw_fd(), events, revents: 0 };
+    // SAFETY: poll only writes to the single pollfd passed to it
+    unsafe { libc::poll(&mut fd, 1, timeout) > 0 }
+}
+
+/// Returns whether reading `f` may wait indefinitely for data, as with pipes, sockets and
+/// terminals.
+fn may_wait(f: &std::fs::File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::fd::AsRawFd;
+    let stream = f.metadata().is_ok_and(|m| m.file_type().is_fifo() || m.file_type().is_socket());
+    // SAFETY: isatty only inspects the descriptor
+    stream || unsafe { libc::isatty(f.as_raw_fd()) } == 1
+}
+
+/// Exits for an error writing to the output at `offset`.
+/// Running out of space exits with 0x24 rather than the generic 0x21, along with how far the
+/// output got.
+#[track_caller]
+pub fn handle_write_err(e: std::io::Error, opts: &Options, offset: u64) -> ! {
+    if e.raw_os_error() == Some(libc::ENOSPC) {
+        eprintln!("No space left on device for {} at offset {offset}", opts.o_f);
+        super::exit_on_err(0x24)
+    }
+    super::handle_err(e, &format!("in file {:?}", opts.o_f), 0x21)
+}
+
+/// Flushes the output for `conv=fsync` and `conv=fdatasync`.
+/// Like GNU dd, fdatasync falls back to fsync where it is not supported. Outputs which can not be
+/// synced at all, like pipes and terminals, have nothing to flush.
+pub fn sync_output(opts: &Options, f: &std::fs::File) {
+    if opts.conv.fsync || opts.conv.fdatasync {
+        let start = std::time::Instant::now();
+        let _busy = crate::watchdog::busy(crate::watchdog::Side::Flush);
+        let unsupported = |e: &std::io::Error| matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOTSUP | libc::ENOSYS));
+        let rc = match if opts.conv.fsync { f.sync_all() } else { f.sync_data() } {
+            Err(e) if unsupported(&e) && !opts.conv.fsync => f.sync_all(),
+            r => r,
+        };
+        match rc {
+            Err(e) if !unsupported(&e) => super::handle_err(e,&format!("syncing {}",opts.o_f), 0x22),
+            _ => {}
+        }
+        super::STATE.sync_time.store(start.elapsed().as_nanos() as u64, std::sync::atomic::Ordering::Relaxed);
     }
 }
\ No newline at end of file
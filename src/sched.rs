@@ -0,0 +1,167 @@
+//! I/O priority, nice value and CPU affinity for `--ionice`, `--nice` and `--cpu-affinity`,
+//! or the `[scheduling]` section of the config.
+//!
+//! Linux keeps all three per thread and new threads inherit them from the thread which starts
+//! them, so [Sched::apply] is called before any thread is started and covers the reader, the
+//! writer and every other thread.
+
+/// I/O scheduling class and level, as used by `ionice(1)`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IoPrio {
+    class: IoClass,
+    /// 0 (highest) to 7, ignored by [IoClass::Idle]
+    level: u8,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+enum IoClass {
+    RealTime = 1,
+    BestEffort = 2,
+    Idle = 3,
+}
+
+impl TryFrom<&str> for IoPrio {
+    type Error = ();
+
+    /// Parses `CLASS[:LEVEL]` where class is 'realtime', 'best-effort' or 'idle', their short
+    /// forms 'rt' and 'be' or the numbers 1 to 3 used by `ionice(1)`.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (class, level) = match value.split_once(':') {
+            Some((c, l)) => (c, Some(l.parse::<u8>().map_err(|_| ())?)),
+            None => (value, None),
+        };
+        let class = match &*class.to_lowercase() {
+            "realtime" | "rt" | "1" => IoClass::RealTime,
+            "best-effort" | "be" | "2" => IoClass::BestEffort,
+            "idle" | "3" => IoClass::Idle,
+            _ => return Err(()),
+        };
+        match level {
+            Some(l) if l > 7 => Err(()),
+            // the default level of the kernel
+            l => Ok(Self { class, level: l.unwrap_or(4) }),
+        }
+    }
+}
+
+/// Parses a list of CPUs such as '0-3,6'.
+pub fn parse_cpu_list(value: &str) -> Result<Vec<usize>, ()> {
+    let mut cpus = Vec::new();
+    for part in value.split(',') {
+        let (first, last): (usize, usize) = match part.split_once('-') {
+            Some((a, b)) => (a.trim().parse().map_err(|_| ())?, b.trim().parse().map_err(|_| ())?),
+            None => {
+                let c = part.trim().parse().map_err(|_| ())?;
+                (c, c)
+            }
+        };
+        if first > last || last >= libc::CPU_SETSIZE as usize {
+            return Err(());
+        }
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// Scheduling settings, anything not given is left as inherited.
+#[derive(Debug, Clone, Default)]
+pub struct Sched {
+    pub ionice: Option<IoPrio>,
+    pub nice: Option<i32>,
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+impl Sched {
+    /// Returns these settings with any given in `other` taking precedence.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            ionice: other.ionice.or(self.ionice),
+            nice: other.nice.or(self.nice),
+            cpu_affinity: other.cpu_affinity.or(self.cpu_affinity),
+        }
+    }
+
+    /// Applies the settings to the calling thread, and so to every thread it starts afterwards.
+    /// A setting which is refused, like a real time class without privileges, is reported and skipped.
+    pub fn apply(&self) {
+        if let Some(p) = self.ionice {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            let prio = (p.class as libc::c_int) << 13 | p.level as libc::c_int;
+            // SAFETY: ioprio_set does not touch memory, 0 is the calling thread
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } < 0 {
+                info!("Unable to set the I/O priority, continuing without it: {}", std::io::Error::last_os_error());
+            }
+        }
+
+        if let Some(n) = self.nice {
+            // SAFETY: as above, on Linux this only changes the calling thread
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, n) } < 0 {
+                info!("Unable to set the nice value, continuing without it: {}", std::io::Error::last_os_error());
+            }
+        }
+
+        if let Some(cpus) = &self.cpu_affinity {
+            // SAFETY: the set is zeroed before use and every cpu is below CPU_SETSIZE, see parse_cpu_list
+            let rc = unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                for c in cpus {
+                    libc::CPU_SET(*c, &mut set);
+                }
+                libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+            };
+            if rc < 0 {
+                info!("Unable to set the CPU affinity, continuing without it: {}", std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("3"), Ok(vec![3]));
+        assert_eq!(parse_cpu_list("0-3,6"), Ok(vec![0, 1, 2, 3, 6]));
+        assert_eq!(parse_cpu_list(" 1 - 2 , 5"), Ok(vec![1, 2, 5]));
+        assert_eq!(parse_cpu_list("4-4"), Ok(vec![4]));
+        assert_eq!(parse_cpu_list("3-1"), Err(()));
+        assert_eq!(parse_cpu_list("0-"), Err(()));
+        assert_eq!(parse_cpu_list("1,,2"), Err(()));
+        assert_eq!(parse_cpu_list("a"), Err(()));
+        assert_eq!(parse_cpu_list(""), Err(()));
+    }
+
+    #[test]
+    fn cpu_list_bounds() {
+        let max = libc::CPU_SETSIZE as usize - 1;
+        assert_eq!(parse_cpu_list(&max.to_string()), Ok(vec![max]));
+        assert_eq!(parse_cpu_list(&(max + 1).to_string()), Err(()));
+        assert_eq!(parse_cpu_list(&format!("{}-{}", max - 1, max + 1)), Err(()));
+    }
+
+    #[test]
+    fn io_classes() {
+        let prio = |class, level| Ok(IoPrio { class, level });
+        assert_eq!(IoPrio::try_from("idle"), prio(IoClass::Idle, 4));
+        assert_eq!(IoPrio::try_from("3"), prio(IoClass::Idle, 4));
+        assert_eq!(IoPrio::try_from("be:0"), prio(IoClass::BestEffort, 0));
+        assert_eq!(IoPrio::try_from("Best-Effort:7"), prio(IoClass::BestEffort, 7));
+        assert_eq!(IoPrio::try_from("2:5"), prio(IoClass::BestEffort, 5));
+        assert_eq!(IoPrio::try_from("realtime:1"), prio(IoClass::RealTime, 1));
+        assert_eq!(IoPrio::try_from("RT"), prio(IoClass::RealTime, 4));
+    }
+
+    #[test]
+    fn io_class_errors() {
+        assert_eq!(IoPrio::try_from("be:8"), Err(()));
+        assert_eq!(IoPrio::try_from("be:-1"), Err(()));
+        assert_eq!(IoPrio::try_from("be:"), Err(()));
+        assert_eq!(IoPrio::try_from("0"), Err(()));
+        assert_eq!(IoPrio::try_from("4"), Err(()));
+        assert_eq!(IoPrio::try_from("none"), Err(()));
+        assert_eq!(IoPrio::try_from(""), Err(()));
+    }
+}
@@ -0,0 +1,193 @@
+//! Signal handling.
+//!
+//! The handlers only store the signal in an atomic, everything else happens in normal context
+//! when the reader notices it through [poll]. The handlers are installed without `SA_RESTART`
+//! and every other thread is started through [spawn] with the signals blocked, so they are
+//! delivered to the reader and interrupt a blocking read.
+//!
+//! A second terminating signal restores the default action and raises the signal again, so a
+//! transfer stuck in a write which never returns can still be stopped without `SIGKILL`.
+//!
+//! Threads waiting on something a signal does not interrupt, like a condition variable, are
+//! woken through [on_stop]. The handler also writes to a pipe read by a watcher thread, which
+//! runs the callbacks and prints the statistics requested by `SIGUSR1` in normal context, in
+//! case the reader is in such a wait.
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// Signals which stop the transfer
+const TERMINATE: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The first terminating signal received, 0 if none
+static PENDING: AtomicI32 = AtomicI32::new(0);
+/// Set by `SIGUSR1` to request the transfer statistics
+static INFO: AtomicBool = AtomicBool::new(false);
+/// Whether data not yet written is dropped once a terminating signal is received, see [Policy]
+static DISCARD: AtomicBool = AtomicBool::new(false);
+/// Write end of the pipe which wakes the watcher thread, -1 until [install]
+static WAKE: AtomicI32 = AtomicI32::new(-1);
+/// Callbacks to run once a terminating signal is received, taken by the watcher thread
+static STOP: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+/// What the writer does with data which was read before the transfer was interrupted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Policy {
+    /// Write everything which was read
+    Drain,
+    /// Drop anything not yet written
+    Discard,
+}
+
+impl TryFrom<&str> for Policy {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match &*value.to_lowercase() {
+            "drain" => Ok(Self::Drain),
+            "discard" => Ok(Self::Discard),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Wakes the watcher thread started by [install].
+fn wake() {
+    // SAFETY: write is async-signal-safe and the byte outlives the call. errno is restored
+    // for the code which was interrupted.
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::write(WAKE.load(Ordering::Relaxed), [0u8].as_ptr().cast(), 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+extern "C" fn handler(sig: libc::c_int) {
+    if sig == libc::SIGUSR1 {
+        INFO.store(true, Ordering::Relaxed);
+        wake();
+    } else if PENDING.compare_exchange(0, sig, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        wake();
+    } else {
+        // SAFETY: signal and raise are async-signal-safe. sig is blocked while the handler
+        // runs, so it is delivered with the default action once the handler returns.
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+fn set() -> libc::sigset_t {
+    // SAFETY: the set is initialized by sigemptyset before use
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for sig in TERMINATE.into_iter().chain([libc::SIGUSR1]) {
+            libc::sigaddset(&mut set, sig);
+        }
+        set
+    }
+}
+
+/// Installs the handlers for the terminating signals and `SIGUSR1`, and starts the thread which
+/// runs the [on_stop] callbacks. `policy` is what happens to the data not written yet.
+pub fn install(policy: Policy) {
+    DISCARD.store(policy == Policy::Discard, Ordering::Relaxed);
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 only writes the two descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        panic!("Error creating signal pipe: {}", std::io::Error::last_os_error());
+    }
+    WAKE.store(fds[1], Ordering::Relaxed);
+    spawn(move || {
+        let mut byte = 0u8;
+        // SAFETY: reads a single byte into `byte`
+        while unsafe { libc::read(fds[0], (&mut byte as *mut u8).cast(), 1) } != 0 {
+            if INFO.swap(false, Ordering::Relaxed) {
+                crate::print_stats();
+            }
+            if pending().is_some() {
+                let callbacks = std::mem::take(&mut *STOP.lock().unwrap_or_else(|e| e.into_inner()));
+                callbacks.into_iter().for_each(|f| f());
+            }
+        }
+    });
+
+    for sig in TERMINATE.into_iter().chain([libc::SIGUSR1]) {
+        // SAFETY: the handler only touches atomics, which is async-signal-safe
+        let rc = unsafe {
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut act.sa_mask);
+            libc::sigaction(sig, &act, std::ptr::null_mut())
+        };
+        if rc != 0 {
+            panic!("Error installing signal handler: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// Runs `f` with the handled signals blocked, so threads it starts do not receive them.
+fn blocked<T>(f: impl FnOnce() -> T) -> T {
+    let set = set();
+    let mut old = set;
+    // SAFETY: both sets are initialized, new threads inherit the mask of their creator
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old) };
+    let r = f();
+    // SAFETY: as above
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &old, std::ptr::null_mut()) };
+    r
+}
+
+/// Spawns a thread which will not receive any of the handled signals.
+pub fn spawn<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    blocked(|| std::thread::spawn(f))
+}
+
+/// Spawns a thread in `scope` which will not receive any of the handled signals.
+pub fn spawn_scoped<'scope, F, T>(scope: &'scope std::thread::Scope<'scope, '_>, f: F) -> std::io::Result<std::thread::ScopedJoinHandle<'scope, T>>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    blocked(|| std::thread::Builder::new().spawn_scoped(scope, f))
+}
+
+/// Runs `f` on the watcher thread once a terminating signal is received, or straight away if one
+/// already was.
+pub fn on_stop(f: impl FnOnce() + Send + 'static) {
+    let mut stop = STOP.lock().unwrap_or_else(|e| e.into_inner());
+    // the handler sets PENDING before waking the watcher, which takes the callbacks under the lock
+    if pending().is_some() {
+        drop(stop);
+        f();
+    } else {
+        stop.push(Box::new(f));
+    }
+}
+
+/// Prints the statistics if they were requested and returns whether the transfer should stop.
+pub fn poll() -> bool {
+    if INFO.swap(false, Ordering::Relaxed) {
+        crate::print_stats();
+    }
+    pending().is_some()
+}
+
+/// Returns whether the transfer is being stopped and the data not written yet dropped.
+pub fn discarding() -> bool {
+    DISCARD.load(Ordering::Relaxed) && pending().is_some()
+}
+
+/// Returns the terminating signal which was received.
+pub fn pending() -> Option<libc::c_int> {
+    match PENDING.load(Ordering::Relaxed) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
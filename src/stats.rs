@@ -0,0 +1,248 @@
+//! Output of transfer statistics, as human readable text, JSON for other programs or the GNU dd summary.
+//!
+//! The JSON schema is stable, fields may be added but existing fields will not be renamed or
+//! change units. Every record is a single line holding one object with an `"event"` field,
+//! which is `"progress"` for periodic records and `"summary"` for the final record.
+//!
+//! `errors` is 1 if the transfer ended because of an error and 0 otherwise, as every error ends
+//! it. With `status=noxfer` the summary leaves out the timing fields `elapsed_ns`,
+//! `read_bytes_per_sec` and `write_bytes_per_sec`, like the transfer line of the other formats.
+
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Human,
+    /// One JSON object per line
+    Json,
+    /// Byte for byte the summary printed by GNU dd
+    Gnu,
+}
+
+impl TryFrom<&str> for Format {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match &*value.to_lowercase() {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "gnu" => Ok(Self::Gnu),
+            _ => Err(()),
+        }
+    }
+}
+
+static OUTPUT: std::sync::OnceLock<(Format, Option<RawFd>)> = std::sync::OnceLock::new();
+
+/// Sets the format and the fd which statistics are written to, stderr is used when `fd` is `None`.
+pub fn init(format: Format, fd: Option<RawFd>) {
+    let _ = OUTPUT.set((format, fd));
+}
+
+pub fn format() -> Format {
+    OUTPUT.get().map(|(f, _)| *f).unwrap_or(Format::Human)
+}
+
+/// Writes `s` to the statistics output.
+pub fn emit(s: &str) {
+    match OUTPUT.get().and_then(|(_, fd)| *fd) {
+        Some(fd) => {
+            // SAFETY: the fd is owned by whoever gave it to us, ManuallyDrop prevents it being closed.
+            let mut f = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+            let _ = f.write_all(s.as_bytes());
+        }
+        None => {
+            let mut e = std::io::stderr().lock();
+            let _ = e.write_all(s.as_bytes());
+            let _ = e.flush();
+        }
+    }
+}
+
+/// Builds a JSON record of the current transfer statistics.
+pub fn json(event: &str) -> String {
+    let s = &crate::STATE;
+    let elapsed = s.elapsed();
+    let read = s.bytes_read.load(Ordering::Relaxed);
+    let written = s.bytes_written.load(Ordering::Relaxed);
+    let rate = |b: usize| if elapsed.is_zero() { 0.0 } else { b as f64 / elapsed.as_secs_f64() };
+
+    let mut j = format!("{{\"event\":\"{event}\"");
+    let mut field = |k: &str, v: String| {
+        j += &format!(",\"{k}\":{v}");
+    };
+    field("records_in", s.read_blk.load(Ordering::Relaxed).to_string());
+    field("records_in_partial", s.read_extra.load(Ordering::Relaxed).to_string());
+    field("records_out", s.write_blk.load(Ordering::Relaxed).to_string());
+    field("records_out_partial", (s.write_extra.load(Ordering::Relaxed) as u8).to_string());
+    field("truncated_records", s.truncated.load(Ordering::Relaxed).to_string());
+    field("bytes_read", read.to_string());
+    field("bytes_written", written.to_string());
+    field("bytes_expected", match s.expected.load(Ordering::Relaxed) {
+        0 => "null".to_string(),
+        n => n.to_string(),
+    });
+    field("bytes_skipped", s.skipped.load(Ordering::Relaxed).to_string());
+    field("sync_ns", s.sync_time.load(Ordering::Relaxed).to_string());
+    if !(event == "summary" && s.status() == crate::Status::NoXFer) {
+        field("elapsed_ns", elapsed.as_nanos().to_string());
+        field("read_bytes_per_sec", format!("{:.3}", rate(read)));
+        field("write_bytes_per_sec", format!("{:.3}", rate(written)));
+    }
+    field("rate_limit", crate::rate::limit().map_or("null".to_string(), |l| l.to_string()));
+    field("errors", (s.failed.load(Ordering::Relaxed) as u8).to_string());
+    if event == "summary" {
+        field("exit_reason", format!("\"{}\"", s.exit_reason().as_str()));
+        if let Some(d) = crate::hash::json() {
+            field("digests", d);
+        }
+    }
+    j += "}\n";
+    j
+}
+
+/// Builds the summary in the format used by GNU dd.
+pub fn gnu() -> String {
+    let s = &crate::STATE;
+    let mut out = String::new();
+    let _ = s.write_records(&mut out);
+
+    let n = s.bytes_written.load(Ordering::Relaxed) as u64;
+    let elapsed = s.elapsed();
+    let si = human_readable(n, 1000, 1, 1);
+    let iec = human_readable(n, 1024, 1, 1);
+    let rate = match elapsed.as_nanos() as u64 {
+        0 => "Infinity B".to_string(),
+        ns => human_readable(n, 1000, 1_000_000_000, ns),
+    };
+    let time = format!("{} s", fmt_g(elapsed.as_secs_f64()));
+
+    // GNU only shows the abbreviations which carry a prefix
+    if si.ends_with(" B") {
+        let unit = if n == 1 { "byte" } else { "bytes" };
+        out += &format!("{n} {unit} copied, {time}, {rate}/s\n");
+    } else if iec.ends_with(" B") {
+        out += &format!("{n} bytes ({si}) copied, {time}, {rate}/s\n");
+    } else {
+        out += &format!("{n} bytes ({si}, {iec}) copied, {time}, {rate}/s\n");
+    }
+    let _ = crate::hash::write_summary(&mut out);
+    out
+}
+
+/// Formats `n * from / to` with a unit prefix of `base`, rounding to nearest.
+///
+/// A port of gnulib's `human_readable` with the options used by GNU dd, exact integer
+/// arithmetic is used when `from` and `to` are multiples of each other.
+fn human_readable(n: u64, base: u64, from: u64, to: u64) -> String {
+    const EXPONENT_MAX: usize = 10;
+    let mut exponent = 0;
+
+    let integer = if to <= from && from.is_multiple_of(to) {
+        n.checked_mul(from / to).map(|amt| (amt, 0, 0))
+    } else if to.is_multiple_of(from) {
+        let divisor = to / from;
+        let r10 = (n % divisor) * 10;
+        let r2 = (r10 % divisor) * 2;
+        let rounding = if r2 < divisor { (0 < r2) as u64 } else { 2 + (divisor < r2) as u64 };
+        Some((n / divisor, r10 / divisor, rounding))
+    } else {
+        None
+    };
+
+    let num = match integer {
+        Some((mut amt, mut tenths, mut rounding)) => {
+            let mut point = None;
+            if base <= amt {
+                while base <= amt && exponent < EXPONENT_MAX {
+                    let r10 = (amt % base) * 10 + tenths;
+                    let r2 = (r10 % base) * 2 + (rounding >> 1);
+                    amt /= base;
+                    tenths = r10 / base;
+                    rounding = if r2 < base { (r2 + rounding != 0) as u64 } else { 2 + (base < r2 + rounding) as u64 };
+                    exponent += 1;
+                }
+
+                if amt < 10 {
+                    if 2 < rounding + (tenths & 1) {
+                        tenths += 1;
+                        rounding = 0;
+                        if tenths == 10 {
+                            amt += 1;
+                            tenths = 0;
+                        }
+                    }
+                    if amt < 10 {
+                        point = Some(tenths);
+                        tenths = 0;
+                        rounding = 0;
+                    }
+                }
+            }
+
+            if 5 < tenths + (0 < rounding + (amt & 1)) as u64 {
+                amt += 1;
+                if amt == base && exponent < EXPONENT_MAX {
+                    exponent += 1;
+                    point = Some(0);
+                    amt = 1;
+                }
+            }
+
+            match point {
+                Some(t) => format!("{amt}.{t}"),
+                None => amt.to_string(),
+            }
+        }
+        None => {
+            let mut amt = n as f64 * (from as f64 / to as f64);
+            let mut e = 1.0;
+            loop {
+                e *= base as f64;
+                exponent += 1;
+                if e * base as f64 > amt || exponent >= EXPONENT_MAX {
+                    break;
+                }
+            }
+            amt /= e;
+
+            match format!("{amt:.1}") {
+                s if s.len() > 4 => format!("{amt:.0}"),
+                s => s,
+            }
+        }
+    };
+
+    let prefix = match exponent {
+        0 => "",
+        1 if base == 1000 => "k",
+        e => &"KMGTPEZYRQ"[e - 1..e],
+    };
+    let i = if base == 1024 && exponent > 0 { "i" } else { "" };
+    format!("{num} {prefix}{i}B")
+}
+
+/// Formats `v` like the C `%g` conversion.
+fn fmt_g(v: f64) -> String {
+    const PRECISION: i32 = 6;
+    if v == 0.0 {
+        return "0".to_string();
+    }
+
+    let sci = format!("{:.*e}", PRECISION as usize - 1, v);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let trim = |s: &str| match s.contains('.') {
+        true => s.trim_end_matches('0').trim_end_matches('.').to_string(),
+        false => s.to_string(),
+    };
+
+    if !(-4..PRECISION).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{sign}{:02}", trim(mantissa), exp.abs())
+    } else {
+        trim(&format!("{:.*}", (PRECISION - 1 - exp) as usize, v))
+    }
+}
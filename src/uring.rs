@@ -0,0 +1,275 @@
+//! io_uring backend, enabled by the `uring` cargo feature.
+//!
+//! Keeps `--queue-depth` blocks in flight at once. Every block has a fixed offset in the input
+//! and the output, so this is only used when both are seekable and the data is copied
+//! unchanged in blocks of the same size. Each buffer is read into and then written from before
+//! it is reused, the buffers are registered with the ring when the kernel allows it.
+
+use std::io::Seek;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::Ordering;
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::buffer::Buffer;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    Idle,
+    Reading,
+    Writing,
+}
+
+/// A buffer and the block it currently holds.
+struct Slot {
+    buf: Buffer,
+    state: State,
+    /// Index of the block from the start of the transfer
+    block: u64,
+    /// Bytes requested by the read
+    want: usize,
+    /// Bytes of the block which have been written
+    written: usize,
+}
+
+/// Returns whether the options allow every block to be copied unchanged at a fixed offset.
+fn eligible(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> bool {
+    let conv = crate::conv::Converter::new(&opts.conv, opts.cbs);
+    !conv.is_active()
+        && opts.i_bs == opts.o_bs
+        && !opts.i_flag.nocache
+        && !opts.o_flag.nocache
+        && !opts.o_flag.append
+        // completions arrive out of order, checkpoints and hashes need the data in order
+        && opts.checkpoint.is_none()
+        && opts.hash.is_empty()
+        && crate::io::seekable(input)
+        && crate::io::seekable(output)
+}
+
+fn position(mut f: &std::fs::File) -> u64 {
+    f.stream_position().unwrap_or(0)
+}
+
+fn ring(depth: u32) -> Option<IoUring> {
+    let ring = IoUring::new(depth).ok()?;
+    let mut probe = io_uring::Probe::new();
+    ring.submitter().register_probe(&mut probe).ok()?;
+    (probe.is_supported(opcode::Read::CODE) && probe.is_supported(opcode::Write::CODE)).then_some(ring)
+}
+
+/// Copies from `input` to `output` through io_uring.
+///
+/// Returns false without transferring anything if the options do not allow it or io_uring
+/// is not available, the threaded pipeline should be used instead.
+pub fn copy(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> bool {
+    if !eligible(opts, input, output) {
+        return false;
+    }
+    let depth = opts.queue_depth.clamp(1, u16::MAX as usize);
+    let Some(mut ring) = ring(depth as u32) else {
+        #[cfg(debug)]
+        eprintln!("io_uring is not available");
+        return false;
+    };
+
+    let bs = opts.i_bs;
+    let i_base = position(input);
+    let o_base = position(output);
+    let mut i_direct = crate::read_write::check_direct(input, &opts.i_f, i_base, bs);
+    let mut o_direct = crate::read_write::check_direct(output, &opts.o_f, o_base, bs);
+    let i_lbs = crate::io::logical_block_size(input);
+    let o_lbs = crate::io::logical_block_size(output);
+
+    let mut slots: Vec<Slot> = (0..depth).map(|_| Slot {
+        buf: Buffer::new(bs),
+        state: State::Idle,
+        block: 0,
+        want: 0,
+        written: 0,
+    }).collect();
+
+    let iovecs: Vec<libc::iovec> = slots.iter_mut().map(|s| libc::iovec {
+        iov_base: s.buf.as_mut_ptr().cast(),
+        iov_len: bs,
+    }).collect();
+    // SAFETY: the buffers outlive the ring, which is dropped first at the end of this function
+    // registering fails when the buffers exceed RLIMIT_MEMLOCK, plain reads and writes still work
+    let fixed = unsafe { ring.submitter().register_buffers(&iovecs) }.is_ok();
+
+    let s = &crate::STATE;
+    let (i_fd, o_fd) = (types::Fd(input.as_raw_fd()), types::Fd(output.as_raw_fd()));
+    // with iflag=count_bytes the count is the number of bytes left to read, otherwise it is in blocks
+    let mut remaining = opts.count.unwrap_or(usize::MAX);
+    let mut next = 0;
+    let mut eof = false;
+    let mut stopping = false;
+    let mut in_flight = 0;
+
+    loop {
+        // start reading into every idle buffer
+        for (i, slot) in slots.iter_mut().enumerate() {
+            if slot.state != State::Idle || eof || stopping || remaining == 0 {
+                continue;
+            }
+            let want = if opts.i_flag.count_bytes { remaining.min(bs) } else { bs };
+            remaining -= if opts.i_flag.count_bytes { want } else { 1 };
+            // the last read of count_bytes may not be aligned
+            if i_direct && !want.is_multiple_of(i_lbs) {
+                crate::io::clear_direct(input).unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.i_f), 0x23));
+                i_direct = false;
+            }
+
+            crate::rate::throttle(crate::rate::Side::Read, want);
+            slot.state = State::Reading;
+            slot.block = next;
+            slot.want = want;
+            slot.written = 0;
+            next += 1;
+            let offset = i_base + slot.block * bs as u64;
+            let ptr = slot.buf.as_mut_ptr();
+            let entry = if fixed {
+                opcode::ReadFixed::new(i_fd, ptr, want as u32, i as u16).offset(offset).build()
+            } else {
+                opcode::Read::new(i_fd, ptr, want as u32).offset(offset).build()
+            };
+            push(&mut ring, entry.user_data(i as u64));
+            in_flight += 1;
+        }
+
+        if in_flight == 0 {
+            break;
+        }
+        let busy: Vec<_> = [(State::Reading, crate::watchdog::Side::Read), (State::Writing, crate::watchdog::Side::Write)].into_iter()
+            .filter(|(state, _)| slots.iter().any(|s| s.state == *state))
+            .map(|(_, side)| crate::watchdog::busy(side))
+            .collect();
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => crate::handle_err(e, "submitting to io_uring", 0x21),
+        }
+        drop(busy);
+        stopping = crate::signal::poll();
+
+        let done: Vec<(usize, i32)> = ring.completion().map(|c| (c.user_data() as usize, c.result())).collect();
+        for (i, res) in done {
+            in_flight -= 1;
+            let slot = &mut slots[i];
+            if res < 0 {
+                let e = std::io::Error::from_raw_os_error(-res);
+                match slot.state {
+                    State::Reading => crate::handle_err(e, &format!("in file {}", opts.i_f), 0x21),
+                    _ => crate::read_write::handle_write_err(e, opts, o_base + slot.block * bs as u64 + slot.written as u64),
+                }
+            }
+            let n = res as usize;
+
+            if slot.state == State::Reading {
+                // reads of seekable files are only short at the end of the input
+                if n < slot.want {
+                    eof = true;
+                }
+                if n == 0 {
+                    slot.state = State::Idle;
+                    continue;
+                }
+                s.bytes_read.fetch_add(n, Ordering::Relaxed);
+                if n < bs {
+                    s.read_extra.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    s.read_blk.fetch_add(1, Ordering::Relaxed);
+                }
+                if stopping && opts.on_interrupt == crate::signal::Policy::Discard {
+                    slot.state = State::Idle;
+                    continue;
+                }
+                slot.buf.set_len(n);
+                slot.state = State::Writing;
+            } else {
+                if n == 0 {
+                    let e = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                    crate::read_write::handle_write_err(e, opts, o_base + slot.block * bs as u64 + slot.written as u64);
+                }
+                slot.written += n;
+                if slot.written == slot.buf.len() {
+                    s.bytes_written.fetch_add(slot.written, Ordering::Relaxed);
+                    if slot.written < bs {
+                        s.write_extra.store(true, Ordering::Relaxed);
+                    } else {
+                        s.write_blk.fetch_add(1, Ordering::Relaxed);
+                    }
+                    slot.buf.set_len(bs);
+                    slot.state = State::Idle;
+                    continue;
+                }
+            }
+
+            // write the block, or what is left of it after a short write
+            let len = slot.buf.len() - slot.written;
+            if o_direct && !len.is_multiple_of(o_lbs) {
+                crate::io::clear_direct(output).unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.o_f), 0x23));
+                o_direct = false;
+            }
+            let offset = o_base + slot.block * bs as u64 + slot.written as u64;
+            // SAFETY: written is less than the length of the buffer
+            let ptr = unsafe { slot.buf.as_ptr().add(slot.written) };
+            let entry = if fixed {
+                opcode::WriteFixed::new(o_fd, ptr, len as u32, i as u16).offset(offset).build()
+            } else {
+                opcode::Write::new(o_fd, ptr, len as u32).offset(offset).build()
+            };
+            push(&mut ring, entry.user_data(i as u64));
+            in_flight += 1;
+        }
+    }
+
+    drop(ring);
+    true
+}
+
+fn push(ring: &mut IoUring, entry: io_uring::squeue::Entry) {
+    // SAFETY: the buffer of every entry is owned by a slot which is not reused until the
+    // entry completes, the queue has room for one entry per slot
+    unsafe { ring.submission().push(&entry) }.expect("io_uring submission queue full");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copies `len` bytes of a pattern with `args` through files named after `name`, returning
+    /// the input and the output. Returns None when io_uring is not available.
+    fn copy_file(name: &str, len: usize, args: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+        ring(1)?;
+        let dir = std::env::temp_dir();
+        let (i_path, o_path) = (dir.join(format!("dd-{name}-in-{}", std::process::id())), dir.join(format!("dd-{name}-out-{}", std::process::id())));
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 + i / 251) as u8).collect();
+        std::fs::write(&i_path, &data).unwrap();
+
+        let opts = crate::Options::from_args(["dd", "--no-cfg"].into_iter().chain(args.split_whitespace()).map(String::from));
+        let input = std::fs::File::open(&i_path).unwrap();
+        let output = std::fs::File::options().read(true).write(true).create(true).truncate(true).open(&o_path).unwrap();
+        assert!(copy(&opts, &input, &output));
+
+        let out = std::fs::read(&o_path).unwrap();
+        let _ = std::fs::remove_file(i_path);
+        let _ = std::fs::remove_file(o_path);
+        Some((data, out))
+    }
+
+    #[test]
+    fn output_matches_input() {
+        // more blocks than the queue depth, with a short one at the end
+        if let Some((data, out)) = copy_file("uring-match", 64 * 4096 + 123, "bs=4096 --queue-depth 4") {
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn count_bytes_stops_mid_block() {
+        if let Some((data, out)) = copy_file("uring-count", 16 * 4096, "bs=4096 count=10000 iflag=count_bytes --queue-depth 3") {
+            assert_eq!(out, data[..10000]);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Read-back verification for `--verify`.
+//!
+//! Once the copy has finished the output is flushed, reopened bypassing the page cache where
+//! possible and compared against the input block by block. When the input can not be read a
+//! second time, because it is a pipe or a device like `/dev/urandom`, or because a conversion
+//! depends on its order, a hash of every block is recorded as it is written and compared instead.
+
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crate::buffer::Buffer;
+use crate::io::{fnv1a, FNV_OFFSET};
+
+/// Bytes read back at once, blocks are hashed in multiples of obs at least this large
+const CHUNK: usize = 1024 * 1024;
+
+/// Hashes of the blocks written so far, when verifying against hashes.
+static RECORD: Mutex<Option<Recorder>> = Mutex::new(None);
+
+struct Recorder {
+    block: usize,
+    hashes: Vec<u64>,
+    /// Hash and length of the block being filled
+    hash: u64,
+    filled: usize,
+}
+
+impl Recorder {
+    fn push(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (self.block - self.filled).min(data.len());
+            self.hash = fnv1a(self.hash, &data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == self.block {
+                self.hashes.push(self.hash);
+                (self.hash, self.filled) = (FNV_OFFSET, 0);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u64> {
+        if self.filled > 0 {
+            self.hashes.push(self.hash);
+        }
+        self.hashes
+    }
+}
+
+/// Records `data` as written to the output, if verifying against hashes.
+pub fn record(data: &[u8]) {
+    if let Some(r) = RECORD.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        r.push(data);
+    }
+}
+
+/// Returns whether hashes are being recorded, which needs the data to pass through the writer.
+pub fn recording() -> bool {
+    RECORD.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// What the output is compared against.
+enum Source {
+    /// The input read again from where the copy started, with the conversions which do not
+    /// depend on the order applied
+    Input(std::fs::File, u64, Box<crate::conv::Converter>),
+    Hashes,
+}
+
+/// Returns why the output can not be verified, if it can not.
+fn refusal(opts: &crate::Options, output: &std::fs::File) -> Option<&'static str> {
+    if !matches!(opts.o_f, crate::io::Target::Path(_)) || !crate::io::seekable(output) {
+        Some("the output must be a file or block device")
+    } else if opts.o_flag.append {
+        Some("the output is opened for appending")
+    } else {
+        None
+    }
+}
+
+fn position(mut f: &std::fs::File) -> u64 {
+    std::io::Seek::stream_position(&mut f).unwrap_or(0)
+}
+
+pub struct Verify {
+    source: Source,
+    output: std::fs::File,
+    o_base: u64,
+    /// Bytes compared as one block
+    block: usize,
+}
+
+impl Verify {
+    /// Prepares to verify the output if `--verify` was given, which has to happen before the
+    /// copy starts in case hashes need to be recorded.
+    /// Exits with 0x51 if the output can not be read back.
+    pub fn start(opts: &crate::Options, input: &std::fs::File, output: &std::fs::File) -> Option<Self> {
+        if !opts.verify {
+            return None;
+        }
+        if let Some(why) = refusal(opts, output) {
+            eprintln!("Can not verify {}, {why}", opts.o_f);
+            crate::exit_on_err(0x51)
+        }
+        let clone = |f: &std::fs::File, target| f.try_clone().unwrap_or_else(|e| crate::handle_err(e, &format!("in file {target}"), 0x51));
+
+        let conv = crate::conv::Converter::new(&opts.conv, opts.cbs);
+        let (source, block) = if crate::io::seekable(input) && !conv.is_ordered() {
+            (Source::Input(clone(input, &opts.i_f), position(input), Box::new(conv)), opts.o_bs)
+        } else {
+            let block = opts.o_bs * (CHUNK / opts.o_bs).max(1);
+            *RECORD.lock().unwrap_or_else(|e| e.into_inner()) = Some(Recorder {
+                block,
+                hashes: Vec::new(),
+                hash: FNV_OFFSET,
+                filled: 0,
+            });
+            (Source::Hashes, block)
+        };
+
+        Some(Self {
+            source,
+            output: clone(output, &opts.o_f),
+            o_base: position(output),
+            block,
+        })
+    }
+
+    /// Reopens the output, bypassing the page cache where possible.
+    fn reopen(&self, opts: &crate::Options) -> std::fs::File {
+        let crate::io::Target::Path(p) = &opts.o_f else {
+            unreachable!("checked by Verify::start")
+        };
+        let open = |flags| std::fs::OpenOptions::new().read(true).custom_flags(flags).open(p);
+        let f = match open(libc::O_DIRECT) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => open(0),
+            r => r,
+        }.unwrap_or_else(|e| crate::handle_err(e, &format!("reopening {}", opts.o_f), 0x51));
+
+        // for when O_DIRECT is not supported or has to be cleared for unaligned reads
+        use std::os::fd::AsRawFd;
+        // SAFETY: posix_fadvise does not touch memory
+        unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        f
+    }
+
+    /// Compares everything written with the source, returning false if anything differs.
+    /// Stops early if a terminating signal is received.
+    pub fn run(mut self, opts: &crate::Options) -> bool {
+        let len = crate::STATE.bytes_written.load(Ordering::Relaxed) as u64;
+        {
+            let _busy = crate::watchdog::busy(crate::watchdog::Side::Flush);
+            self.output.sync_data().unwrap_or_else(|e| crate::handle_err(e, &format!("syncing {}", opts.o_f), 0x22));
+        }
+        let f = self.reopen(opts);
+        let lbs = crate::io::logical_block_size(&f);
+        let hashes = match self.source {
+            Source::Hashes => RECORD.lock().unwrap_or_else(|e| e.into_inner()).take().map(Recorder::finish).unwrap_or_default(),
+            Source::Input(..) => Vec::new(),
+        };
+
+        // a whole number of blocks, and of the logical block size for O_DIRECT
+        let per = (CHUNK / self.block).max(1) * self.block;
+        let mut out = Buffer::new(per);
+        let mut expected = Buffer::new(per);
+        let mut first = None;
+        let mut bad = 0u64;
+        let mut pos = 0u64;
+        while pos < len && !crate::signal::poll() {
+            let want = (len - pos).min(per as u64) as usize;
+            // the offset of the copy or the final read may not be aligned
+            if crate::io::is_direct(&f) && !(want.is_multiple_of(lbs) && (self.o_base + pos).is_multiple_of(lbs as u64)) {
+                crate::io::clear_direct(&f).unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.o_f), 0x23));
+            }
+            let busy = crate::watchdog::busy(crate::watchdog::Side::Read);
+            let got = crate::io::read_at(&f, &mut out[..want], self.o_base + pos)
+                .unwrap_or_else(|e| crate::handle_err(e, &format!("reading back {}", opts.o_f), 0x21));
+            let have = match &mut self.source {
+                Source::Input(input, i_base, conv) => {
+                    let n = crate::io::read_at(input, &mut expected[..want], *i_base + pos)
+                        .unwrap_or_else(|e| crate::handle_err(e, &format!("in file {}", opts.i_f), 0x21));
+                    conv.translate(&mut expected[..n]);
+                    n
+                }
+                Source::Hashes => 0,
+            };
+            drop(busy);
+
+            for start in (0..want).step_by(self.block) {
+                let end = (start + self.block).min(want);
+                let o = &out[start..end.min(got)];
+                let mismatch = match self.source {
+                    Source::Input(..) => {
+                        let i = &expected[start.min(have)..end.min(have)];
+                        (o != i).then(|| o.iter().zip(i).position(|(a, b)| a != b).unwrap_or(o.len().min(i.len())))
+                    }
+                    Source::Hashes => {
+                        let index = ((pos + start as u64) / self.block as u64) as usize;
+                        (hashes.get(index) != Some(&fnv1a(FNV_OFFSET, o))).then_some(0)
+                    }
+                };
+                if let Some(at) = mismatch {
+                    bad += 1;
+                    first.get_or_insert(self.o_base + pos + (start + at) as u64);
+                }
+            }
+            pos += want as u64;
+        }
+
+        let blocks = len.div_ceil(self.block as u64);
+        match first {
+            Some(at) => {
+                // a hash only tells which block differs, not where in it
+                let what = match self.source {
+                    Source::Input(..) => "first mismatch",
+                    Source::Hashes => "first mismatching block",
+                };
+                eprintln!("Verifying {} failed, {what} at offset {at}, {bad} of {blocks} blocks of {} bytes differ", opts.o_f, self.block);
+                false
+            }
+            None => {
+                if crate::signal::pending().is_none() {
+                    info!("Verified {len} bytes of {}", opts.o_f);
+                }
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Stall detection for `--stall-timeout`.
+//!
+//! Every read and write holds a [Busy] guard while it is in the kernel. A thread samples the
+//! byte counters in [crate::GlobalState] and, if they have not moved for the timeout while a
+//! read or write was outstanding, reports the side which is stuck and exits with 0x25.
+//! Waiting on `--rate-limit` or for a free buffer holds no guard, so it is never a stall.
+//! Flushing the output with fsync can take minutes after a large transfer without moving the
+//! counters, so a [Side::Flush] guard is only reported once it has been held for
+//! [FLUSH_ALLOWANCE] times the timeout.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How many times the stall timeout a flush may take before it is reported
+pub const FLUSH_ALLOWANCE: u32 = 10;
+
+/// Reads, writes and flushes which have not returned yet, indexed by [Side]
+static BUSY: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Side {
+    Read,
+    Write,
+    /// Syncing the output, which is allowed [FLUSH_ALLOWANCE] times the timeout
+    Flush,
+}
+
+/// Marks a read or write as outstanding until dropped.
+#[must_use]
+pub struct Busy(Side);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY[self.0 as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Marks a read or write on `side` as outstanding until the guard is dropped.
+pub fn busy(side: Side) -> Busy {
+    BUSY[side as usize].fetch_add(1, Ordering::Relaxed);
+    Busy(side)
+}
+
+fn is_busy(side: Side) -> bool {
+    BUSY[side as usize].load(Ordering::Relaxed) > 0
+}
+
+/// Starts the thread watching for a stall of at least `timeout`.
+pub fn spawn(opts: &crate::Options, timeout: Duration) {
+    let opts = opts.clone();
+    let step = (timeout / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    crate::signal::spawn(move || {
+        let s = &crate::STATE;
+        let counters = || (s.bytes_read.load(Ordering::Relaxed), s.bytes_written.load(Ordering::Relaxed));
+        let mut last = (Instant::now(), counters());
+        loop {
+            std::thread::sleep(step);
+            let now = counters();
+            if now != last.1 || !(is_busy(Side::Read) || is_busy(Side::Write) || is_busy(Side::Flush)) {
+                last = (Instant::now(), now);
+            } else if let Some(side) = stalled(last.0.elapsed(), timeout) {
+                stall(&opts, side, last.0.elapsed());
+            }
+        }
+    });
+}
+
+/// Returns the side which has been stuck for `idle` without progress, if that is a stall.
+/// A flush in progress is what the transfer waits on, whatever else is outstanding.
+fn stalled(idle: Duration, timeout: Duration) -> Option<Side> {
+    if is_busy(Side::Flush) {
+        return (idle >= timeout * FLUSH_ALLOWANCE).then_some(Side::Flush);
+    }
+    if idle < timeout {
+        return None;
+    }
+    match (is_busy(Side::Read), is_busy(Side::Write)) {
+        (true, false) => Some(Side::Read),
+        (false, false) => None,
+        _ => Some(Side::Write),
+    }
+}
+
+fn stall(opts: &crate::Options, side: Side, idle: Duration) -> ! {
+    let s = &crate::STATE;
+    let i_off = s.skipped.load(Ordering::Relaxed) + s.bytes_read.load(Ordering::Relaxed) as u64;
+    let o_off = opts.seek_bytes().unwrap_or(0) + s.bytes_written.load(Ordering::Relaxed) as u64;
+    let what = match (side, is_busy(Side::Read)) {
+        (Side::Flush, _) => format!("flush of {} at offset {o_off}", opts.o_f),
+        (Side::Read, _) => format!("read from {} at offset {i_off}", opts.i_f),
+        // io_uring may wait on both
+        (Side::Write, true) => format!("copy from {} at offset {i_off} to {} at offset {o_off}", opts.i_f, opts.o_f),
+        (Side::Write, false) => format!("write to {} at offset {o_off}", opts.o_f),
+    };
+    if crate::STATE.status() == crate::Status::Progress {
+        eprintln!();
+    }
+    eprintln!("No progress for {:.1} s, {what} has not completed", idle.as_secs_f64());
+
+    s.failed.store(true, Ordering::Relaxed);
+    s.exit_reason.store(crate::ExitReason::Stalled as u8, Ordering::Relaxed);
+    crate::print_stats();
+    std::process::exit(0x25)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_is_reported_after_its_allowance() {
+        let timeout = Duration::from_millis(500);
+        let _busy = busy(Side::Flush);
+        assert_eq!(stalled(timeout, timeout), None);
+        assert_eq!(stalled(timeout * FLUSH_ALLOWANCE - Duration::from_millis(1), timeout), None);
+        assert_eq!(stalled(timeout * FLUSH_ALLOWANCE, timeout), Some(Side::Flush));
+    }
+}
@@ -0,0 +1,90 @@
+//! Interrupting a transfer with `--checkpoint` and finishing it with `--resume`.
+//!
+//! `--rate-limit` keeps the first run going long enough to be interrupted part way through.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const LEN: usize = 8 * 1024 * 1024;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dd-checkpoint-{name}-{}", std::process::id()))
+}
+
+fn dd(args: &[String]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"));
+    cmd.arg("--no-cfg").args(args).stderr(Stdio::null());
+    cmd
+}
+
+/// Runs `args` with `--checkpoint cp` at a limited rate and interrupts it after a second,
+/// returning the length of the output.
+fn interrupt(args: &[String], output: &Path, cp: &Path) -> usize {
+    let mut first = args.to_vec();
+    first.extend(["--rate-limit".to_string(), "2M".to_string(), "--checkpoint".to_string(), cp.display().to_string()]);
+    let mut child = dd(&first).spawn().unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap().success());
+    assert_eq!(child.wait().unwrap().code(), Some(128 + libc::SIGINT));
+    std::fs::metadata(output).unwrap().len() as usize
+}
+
+/// Copies [LEN] bytes with `extra` arguments, interrupted after a second and then resumed,
+/// returning the input and the output.
+fn interrupt_and_resume(name: &str, extra: &[&str]) -> (Vec<u8>, Vec<u8>) {
+    let (input, output, cp) = (path(&format!("{name}-in")), path(&format!("{name}-out")), path(&format!("{name}-cp")));
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 31 + i / 4099) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+    let _ = std::fs::remove_file(&output);
+
+    let mut args = vec![format!("if={}", input.display()), format!("of={}", output.display()), "bs=64K".to_string()];
+    args.extend(extra.iter().map(|a| a.to_string()));
+
+    let partial = interrupt(&args, &output, &cp);
+    assert!(partial > 0 && partial < data.len(), "interrupted after {partial} bytes");
+
+    let mut second = args;
+    second.extend(["--resume".to_string(), cp.display().to_string()]);
+    assert!(dd(&second).status().unwrap().success());
+    assert!(!cp.exists(), "checkpoint left behind after the transfer completed");
+
+    let out = std::fs::read(&output).unwrap();
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+    (data, out)
+}
+
+#[test]
+fn resume_completes_copy() {
+    let (data, out) = interrupt_and_resume("whole", &[]);
+    assert!(out == data, "output differs from the input");
+}
+
+#[test]
+fn resume_keeps_count() {
+    let (data, out) = interrupt_and_resume("count", &["count=100"]);
+    assert!(out == data[..100 * 64 * 1024], "output differs from the start of the input");
+}
+
+#[test]
+fn resume_refuses_hash_and_verify() {
+    let (input, output, cp) = (path("digest-in"), path("digest-out"), path("digest-cp"));
+    std::fs::write(&input, vec![7u8; LEN]).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let args = [format!("if={}", input.display()), format!("of={}", output.display()), "bs=64K".to_string()];
+    let partial = interrupt(&args, &output, &cp);
+
+    for extra in ["--hash=sha256", "--verify"] {
+        let mut resumed = args.to_vec();
+        resumed.extend([extra.to_string(), "--resume".to_string(), cp.display().to_string()]);
+        let out = dd(&resumed).stderr(Stdio::piped()).output().unwrap();
+        let err = String::from_utf8(out.stderr).unwrap();
+        assert_eq!(out.status.code(), Some(0x43), "{err}");
+        assert!(err.contains("would only cover the part copied after resuming"), "{err}");
+        assert_eq!(std::fs::metadata(&output).unwrap().len() as usize, partial);
+    }
+    for p in [input, output, cp] {
+        let _ = std::fs::remove_file(p);
+    }
+}
@@ -0,0 +1,69 @@
+//! Compares `--stats-format=gnu` against summaries recorded from GNU dd (coreutils 9.1) using
+//! `dd if=/dev/zero of=/dev/null bs=65536 count=N iflag=count_bytes`.
+
+use std::process::Command;
+
+const RECORDED: &[(u64, &str)] = &[
+    (0, "0+0 records in\n0+0 records out\n0 bytes copied"),
+    (1, "0+1 records in\n0+1 records out\n1 byte copied"),
+    (2, "0+1 records in\n0+1 records out\n2 bytes copied"),
+    (999, "0+1 records in\n0+1 records out\n999 bytes copied"),
+    (1000, "0+1 records in\n0+1 records out\n1000 bytes (1.0 kB) copied"),
+    (1023, "0+1 records in\n0+1 records out\n1023 bytes (1.0 kB) copied"),
+    (1024, "0+1 records in\n0+1 records out\n1024 bytes (1.0 kB, 1.0 KiB) copied"),
+    (1536, "0+1 records in\n0+1 records out\n1536 bytes (1.5 kB, 1.5 KiB) copied"),
+    (9999, "0+1 records in\n0+1 records out\n9999 bytes (10 kB, 9.8 KiB) copied"),
+    (10239, "0+1 records in\n0+1 records out\n10239 bytes (10 kB, 10 KiB) copied"),
+    (99950, "1+1 records in\n1+1 records out\n99950 bytes (100 kB, 98 KiB) copied"),
+    (999499, "15+1 records in\n15+1 records out\n999499 bytes (999 kB, 976 KiB) copied"),
+    (999500, "15+1 records in\n15+1 records out\n999500 bytes (1.0 MB, 976 KiB) copied"),
+    (1048576, "16+0 records in\n16+0 records out\n1048576 bytes (1.0 MB, 1.0 MiB) copied"),
+    (1500000, "22+1 records in\n22+1 records out\n1500000 bytes (1.5 MB, 1.4 MiB) copied"),
+];
+
+fn summary(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "if=/dev/zero", "of=/dev/null", "--stats-format=gnu"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{out:?}");
+    String::from_utf8(out.stderr).unwrap()
+}
+
+/// Checks the part after "copied" looks like ", 0.0123 s, 12.3 MB/s".
+fn check_time_and_rate(tail: &str) {
+    let parts: Vec<&str> = tail.trim_end_matches('\n').split(", ").collect();
+    assert_eq!(parts.len(), 3, "{tail:?}");
+    assert_eq!(parts[0], "");
+
+    let time = parts[1].strip_suffix(" s").unwrap();
+    time.parse::<f64>().unwrap();
+    assert!(time.len() <= 11, "{time} is not %g");
+
+    let (num, unit) = parts[2].split_once(' ').unwrap();
+    assert!(num.parse::<f64>().unwrap() < 1000.0, "{num}");
+    assert!(num.len() <= 4, "{num}");
+    assert!(["B/s", "kB/s", "MB/s", "GB/s", "TB/s"].contains(&unit), "{unit}");
+}
+
+#[test]
+fn matches_gnu() {
+    for (n, expected) in RECORDED {
+        let out = summary(&["bs=65536", &format!("count={n}"), "iflag=count_bytes"]);
+        let (head, tail) = out.rsplit_once(" copied").unwrap();
+        assert_eq!(format!("{head} copied"), *expected, "count={n}");
+        check_time_and_rate(tail);
+    }
+}
+
+#[test]
+fn noxfer_prints_records_only() {
+    let out = summary(&["bs=512", "count=3", "status=noxfer"]);
+    assert_eq!(out, "3+0 records in\n3+0 records out\n");
+}
+
+#[test]
+fn none_is_silent() {
+    assert_eq!(summary(&["bs=512", "count=3", "status=none"]), "");
+}
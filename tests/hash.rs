@@ -0,0 +1,139 @@
+//! `--hash` and `--hash-file` checked against `sha256sum` and `cksum` run on the files afterwards.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const LEN: usize = 5 * 1024 * 1024;
+const BS: usize = 64 * 1024;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dd-hash-{name}-{}", std::process::id()))
+}
+
+/// Writes mixed case text to a new input file, so `conv=ucase` changes the output.
+fn input(name: &str) -> PathBuf {
+    let p = path(name);
+    let data: Vec<u8> = (0..LEN).map(|i| b"aBcDeFgHiJ\n"[(i + i / 4093) % 11]).collect();
+    std::fs::write(&p, data).unwrap();
+    p
+}
+
+fn sha256sum(p: &Path) -> String {
+    let out = Command::new("sha256sum").arg(p).output().unwrap();
+    assert!(out.status.success());
+    String::from_utf8(out.stdout).unwrap().split_whitespace().next().unwrap().to_string()
+}
+
+fn dd(args: &[String]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"));
+    cmd.arg("--no-cfg").args(args).stderr(Stdio::piped());
+    cmd
+}
+
+/// Returns the digest on the summary line starting with `prefix`.
+fn summary(err: &str, prefix: &str) -> String {
+    let line = err.lines().find(|l| l.starts_with(prefix)).unwrap_or_else(|| panic!("no '{prefix}' in {err}"));
+    line[prefix.len()..].trim().to_string()
+}
+
+#[test]
+fn hash_file_matches_sha256sum() {
+    let (i, o, sums) = (input("file-in"), path("file-out"), path("file-sums"));
+    let _ = std::fs::remove_file(&o);
+    let out = dd(&[format!("if={}", i.display()), format!("of={}", o.display()), format!("--hash-file={}", sums.display()), "conv=ucase".to_string()])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let expected = format!("{}  {}\n{}  {}\n", sha256sum(&i), i.display(), sha256sum(&o), o.display());
+    assert_eq!(std::fs::read_to_string(&sums).unwrap(), expected);
+    assert_ne!(sha256sum(&i), sha256sum(&o));
+    for p in [i, o, sums] {
+        let _ = std::fs::remove_file(p);
+    }
+}
+
+#[test]
+fn tagged_hash_file_checks() {
+    let (i, o, sums) = (input("tag-in"), path("tag-out"), path("tag-sums"));
+    let _ = std::fs::remove_file(&o);
+    let status = dd(&[format!("if={}", i.display()), format!("of={}", o.display()), format!("--hash-file={}", sums.display()), "--hash=sha256,md5".to_string(), "conv=ucase".to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // cksum only checks files from coreutils 9
+    if Command::new("cksum").arg("--help").output().is_ok_and(|o| String::from_utf8_lossy(&o.stdout).contains("--check")) {
+        let check = Command::new("cksum").arg("-c").arg(&sums).stdout(Stdio::null()).status().unwrap();
+        assert!(check.success(), "{}", std::fs::read_to_string(&sums).unwrap());
+    }
+    for p in [i, o, sums] {
+        let _ = std::fs::remove_file(p);
+    }
+}
+
+#[test]
+fn hash_file_leaves_out_uncheckable_algorithms() {
+    let (i, o, sums) = (input("mixed-in"), path("mixed-out"), path("mixed-sums"));
+    let _ = std::fs::remove_file(&o);
+    let out = dd(&[format!("if={}", i.display()), format!("of={}", o.display()), format!("--hash-file={}", sums.display()), "--hash=sha256,blake3".to_string()])
+        .output()
+        .unwrap();
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(out.status.success(), "{err}");
+    assert_eq!(err.matches("Leaving blake3 out of --hash-file").count(), 1, "{err}");
+    assert_eq!(summary(&err, "blake3 of output:").len(), 64, "{err}");
+
+    let expected = format!("{}  {}\n{}  {}\n", sha256sum(&i), i.display(), sha256sum(&o), o.display());
+    assert_eq!(std::fs::read_to_string(&sums).unwrap(), expected);
+    for p in [i, o, sums] {
+        let _ = std::fs::remove_file(p);
+    }
+}
+
+#[test]
+fn hash_file_without_checkable_algorithm() {
+    let out = dd(&["if=/dev/zero".to_string(), "count=1".to_string(), "--hash=blake3".to_string(), format!("--hash-file={}", path("blake3").display())])
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(out.status.success(), "{err}");
+    assert!(err.contains("Not writing --hash-file"), "{err}");
+    assert!(err.contains("blake3 of output:"), "{err}");
+    assert!(!path("blake3").exists());
+}
+
+#[test]
+fn interrupted_output_digest_covers_written_data() {
+    let (i, o, prefix) = (input("int-in"), path("int-out"), path("int-prefix"));
+    let _ = std::fs::remove_file(&o);
+    let args = [
+        format!("if={}", i.display()),
+        format!("of={}", o.display()),
+        format!("bs={BS}"),
+        "conv=ucase".to_string(),
+        "--hash=sha256".to_string(),
+        "--rate-limit=4M".to_string(),
+        "--on-interrupt=discard".to_string(),
+    ];
+    let child = dd(&args).spawn().unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+    assert!(Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap().success());
+    let out = child.wait_with_output().unwrap();
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert_eq!(out.status.code(), Some(128 + libc::SIGINT), "{err}");
+
+    let written = std::fs::metadata(&o).unwrap().len() as usize;
+    assert!(written > 0 && written < LEN, "interrupted after {written} bytes");
+    assert_eq!(summary(&err, "sha256 of output:"), sha256sum(&o));
+
+    // everything read was hashed, whether or not it was written
+    let records: usize = err.split("+0 records in").next().unwrap().trim().parse().unwrap();
+    std::fs::write(&prefix, &std::fs::read(&i).unwrap()[..records * BS]).unwrap();
+    assert_eq!(summary(&err, "sha256 of input:"), sha256sum(&prefix));
+    for p in [i, o, prefix] {
+        let _ = std::fs::remove_file(p);
+    }
+}
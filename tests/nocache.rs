@@ -0,0 +1,88 @@
+//! `iflag=nocache` and `oflag=nocache` leaving the data out of the page cache, checked with
+//! `mincore`. Skipped on file systems like tmpfs, whose pages can not be dropped.
+
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const LEN: usize = 32 * 1024 * 1024 + 12345;
+
+fn path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("dd-nocache-{name}-{}", std::process::id()))
+}
+
+/// Returns the number of pages of `p` in the page cache.
+fn resident(p: &Path) -> usize {
+    let f = std::fs::File::open(p).unwrap();
+    let len = f.metadata().unwrap().len() as usize;
+    let page = 4096;
+    let mut pages = vec![0u8; len.div_ceil(page)];
+    // SAFETY: the mapping is only passed to mincore and unmapped before returning, pages has
+    // room for one byte per page
+    unsafe {
+        let addr = libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, f.as_raw_fd(), 0);
+        assert_ne!(addr, libc::MAP_FAILED);
+        assert_eq!(libc::mincore(addr, len, pages.as_mut_ptr()), 0);
+        libc::munmap(addr, len);
+    }
+    pages.iter().filter(|p| *p & 1 == 1).count()
+}
+
+/// Drops `p` from the page cache, returning false if it stays there.
+fn can_drop(p: &Path) -> bool {
+    let f = std::fs::File::open(p).unwrap();
+    f.sync_all().unwrap();
+    // SAFETY: posix_fadvise does not touch memory
+    unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    resident(p) == 0
+}
+
+fn copy(name: &str, args: &[&str]) -> (usize, usize) {
+    let (input, output) = (path(&format!("{name}-in")), path(&format!("{name}-out")));
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 3 + i / 4111) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+    // dirty pages can not be dropped
+    std::fs::File::open(&input).unwrap().sync_all().unwrap();
+    let _ = std::fs::remove_file(&output);
+    // read back into the cache, so only the copy can drop it
+    assert_eq!(std::fs::read(&input).unwrap(), data);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "bs=1M", "status=none", &format!("if={}", input.display()), &format!("of={}", output.display())])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let pages = (resident(&input), resident(&output));
+    assert!(std::fs::read(&output).unwrap() == data, "output differs from the input");
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+    pages
+}
+
+fn droppable() -> bool {
+    let p = path("probe");
+    std::fs::write(&p, vec![1u8; 1024 * 1024]).unwrap();
+    let ok = can_drop(&p);
+    let _ = std::fs::remove_file(p);
+    ok
+}
+
+#[test]
+fn nocache_drops_both_sides() {
+    if !droppable() {
+        return;
+    }
+    assert_eq!(copy("plain", &["iflag=nocache", "oflag=nocache"]), (0, 0));
+}
+
+#[test]
+fn parallel_nocache_drops_both_sides() {
+    if !droppable() {
+        return;
+    }
+    // without nocache the workers leave both files cached
+    let (i, o) = copy("jobs-cached", &["--jobs", "4"]);
+    assert!(i > 0 && o > 0);
+    assert_eq!(copy("jobs", &["--jobs", "4", "iflag=nocache", "oflag=nocache"]), (0, 0));
+}
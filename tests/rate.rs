@@ -0,0 +1,55 @@
+//! `--rate-limit` spreading a transfer over the expected time.
+
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Copies 1.5 MiB at `args`, returning how long it took.
+fn elapsed(args: &[&str]) -> Duration {
+    let start = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "if=/dev/zero", "of=/dev/null", "bs=64K", "count=24"])
+        .args(args)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    start.elapsed()
+}
+
+#[test]
+fn limit_bounds_elapsed_time() {
+    // 1.5s at the limit, the upper bound is loose as the machine may be busy
+    let t = elapsed(&["--rate-limit", "1M"]);
+    assert!(t >= Duration::from_millis(1200), "finished in {t:?}");
+    assert!(t < Duration::from_secs(6), "took {t:?}");
+}
+
+#[test]
+fn threaded_pipeline_is_limited() {
+    let t = elapsed(&["--rate-limit", "1M", "conv=ucase"]);
+    assert!(t >= Duration::from_millis(1200), "finished in {t:?}");
+    assert!(t < Duration::from_secs(6), "took {t:?}");
+}
+
+#[test]
+fn limit_applies_while_draining() {
+    let out = std::env::temp_dir().join(format!("dd-rate-drain-{}", std::process::id()));
+    let _ = std::fs::remove_file(&out);
+    let start = Instant::now();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "if=/dev/zero", "bs=64K", "count=1000", "conv=ucase", "--rate-limit", "1M"])
+        .arg(format!("of={}", out.display()))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap().success());
+    assert_eq!(child.wait().unwrap().code(), Some(128 + libc::SIGINT));
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let written = std::fs::metadata(&out).unwrap().len() as f64;
+    let _ = std::fs::remove_file(out);
+    // the reader is limited too, so little is buffered when the signal arrives
+    let most = elapsed * 1024.0 * 1024.0 + 256.0 * 1024.0;
+    assert!(written <= most, "wrote {written} bytes in {elapsed:.2} s");
+}
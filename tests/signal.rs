@@ -0,0 +1,41 @@
+//! Stopping a transfer whose writer is stuck, with stdout connected to a pipe which is never read.
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn kill(pid: u32, sig: &str) {
+    assert!(Command::new("kill").args([sig, &pid.to_string()]).status().unwrap().success());
+}
+
+#[test]
+fn second_signal_stops_stuck_writer() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "if=/dev/zero", "bs=1M", "count=64", "iflag=fullblock"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // held open so the writer blocks once the pipe is full
+    let _stdout = child.stdout.take();
+
+    std::thread::sleep(Duration::from_millis(300));
+    // the writer can not finish draining, so the first signal can not end the transfer
+    kill(child.id(), "-INT");
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(child.try_wait().unwrap().is_none(), "exited after the first signal");
+    kill(child.id(), "-TERM");
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(s) = child.try_wait().unwrap() {
+            break s;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            let _ = child.kill();
+            panic!("still running after the second signal");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
@@ -0,0 +1,57 @@
+//! `--stall-timeout` with pipes which are never read from or written to.
+//!
+//! `iflag=fullblock` keeps the transfer on the threaded pipeline, without it the same copies
+//! use `splice`, which has to report the side it is waiting on as well.
+
+use std::process::{Command, Stdio};
+
+/// Runs with stdin and stdout connected to pipes which are left alone, returning the exit
+/// code and stderr.
+fn run(args: &[&str]) -> (Option<i32>, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .arg("--no-cfg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // held open until the child exits, so reads and writes block instead of failing
+    let _stdin = child.stdin.take();
+    let _stdout = child.stdout.take();
+    let out = child.wait_with_output().unwrap();
+    (out.status.code(), String::from_utf8(out.stderr).unwrap())
+}
+
+#[test]
+fn blocked_write() {
+    let (code, err) = run(&["if=/dev/zero", "bs=1M", "count=16", "iflag=fullblock", "--stall-timeout", "500ms"]);
+    assert_eq!(code, Some(0x25), "{err}");
+    assert!(err.contains("write to stdout at offset"), "{err}");
+    assert!(err.contains("records out"), "{err}");
+}
+
+#[test]
+fn blocked_read() {
+    let (code, err) = run(&["of=/dev/null", "iflag=fullblock", "--stall-timeout", "500ms", "--stats-format", "json"]);
+    assert_eq!(code, Some(0x25), "{err}");
+    assert!(err.contains("read from stdin at offset 0"), "{err}");
+    assert!(err.contains("\"exit_reason\":\"stalled\""), "{err}");
+}
+
+#[test]
+fn blocked_splice_write() {
+    let input = std::env::temp_dir().join(format!("dd-stall-splice-{}", std::process::id()));
+    std::fs::write(&input, vec![0u8; 16 * 1024 * 1024]).unwrap();
+    let (code, err) = run(&[&format!("if={}", input.display()), "bs=1M", "--stall-timeout", "500ms"]);
+    let _ = std::fs::remove_file(input);
+    assert_eq!(code, Some(0x25), "{err}");
+    assert!(err.contains("write to stdout at offset"), "{err}");
+}
+
+#[test]
+fn blocked_splice_read() {
+    let (code, err) = run(&["of=/dev/null", "--stall-timeout", "500ms"]);
+    assert_eq!(code, Some(0x25), "{err}");
+    assert!(err.contains("read from stdin at offset 0"), "{err}");
+}
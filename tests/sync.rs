@@ -0,0 +1,19 @@
+//! `conv=fsync` and `conv=fdatasync` with outputs which can not be synced.
+
+use std::process::{Command, Stdio};
+
+#[test]
+fn pipe_output_has_nothing_to_sync() {
+    for conv in ["conv=fsync", "conv=fdatasync"] {
+        let out = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+            .args(["--no-cfg", "if=/dev/zero", "bs=4K", "count=3", conv])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap();
+        let err = String::from_utf8(out.stderr).unwrap();
+        assert!(out.status.success(), "{conv}: {err}");
+        assert_eq!(out.stdout.len(), 3 * 4096, "{conv}");
+        assert!(err.contains("3+0 records out"), "{conv}: {err}");
+    }
+}
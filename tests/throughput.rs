@@ -0,0 +1,91 @@
+//! Throughput benchmark copying from `/dev/zero` to a file on tmpfs, so memory bandwidth and
+//! syscall overhead are the only limits. Each copy must reach [GNU_FRACTION] of the speed of
+//! GNU dd run with the same arguments, or when it is not installed a fraction of the speed of
+//! copying the same amount with `memcpy`. Both are measured on the same machine, so the floors
+//! do not depend on the hardware. The best of [RUNS] runs is used to smooth out noise.
+//!
+//! Run with `cargo test --release --test throughput -- --ignored --nocapture --test-threads=1`.
+
+use std::process::Command;
+use std::time::Instant;
+
+const DIR: &str = "/dev/shm";
+const TOTAL: usize = 1024 * 1024 * 1024;
+const RUNS: usize = 3;
+/// Fraction of the speed of GNU dd which has to be reached. The reader and writer are separate
+/// threads, which only pays off with a second CPU; on one CPU about 0.75 is reached.
+const GNU_FRACTION: f64 = 0.65;
+
+fn run(program: &str, bs: usize) -> f64 {
+    let out = format!("{DIR}/disk-destroyer-bench-{}", std::process::id());
+    let args = [
+        "if=/dev/zero".to_string(),
+        format!("of={out}"),
+        format!("bs={bs}"),
+        format!("count={}", TOTAL / bs),
+        "status=none".to_string(),
+    ];
+
+    let mut cmd = Command::new(program);
+    if program != "dd" {
+        cmd.arg("--no-cfg");
+    }
+    let start = Instant::now();
+    let status = cmd.args(&args).status().unwrap();
+    let elapsed = start.elapsed();
+    let _ = std::fs::remove_file(&out);
+    assert!(status.success());
+
+    TOTAL as f64 / elapsed.as_secs_f64() / 1e9
+}
+
+/// Returns the speed of copying [TOTAL] bytes between two buffers in memory, in GB/s.
+fn memcpy() -> f64 {
+    const CHUNK: usize = 64 * 1024 * 1024;
+    let src = vec![1u8; CHUNK];
+    let mut dst = vec![0u8; CHUNK];
+    // fault the pages in before timing
+    dst.copy_from_slice(&src);
+
+    let start = Instant::now();
+    for _ in 0..TOTAL / CHUNK {
+        dst.copy_from_slice(std::hint::black_box(&src));
+        std::hint::black_box(&mut dst);
+    }
+    TOTAL as f64 / start.elapsed().as_secs_f64() / 1e9
+}
+
+/// Returns the best speed of [RUNS] copies with `program`.
+fn best(program: &str, bs: usize) -> f64 {
+    (0..RUNS).map(|_| run(program, bs)).fold(0.0, f64::max)
+}
+
+/// Runs the copy with `bs`, failing if it is slower than [GNU_FRACTION] of GNU dd, or than
+/// `1/divisor` of [memcpy] without GNU dd.
+fn bench(bs: usize, divisor: f64) {
+    let baseline = memcpy();
+    let ours = best(env!("CARGO_BIN_EXE_disk-destroyer"), bs);
+    let gnu = Command::new("dd").arg("--version").output().is_ok_and(|o| o.status.success()).then(|| best("dd", bs));
+    print!("bs={bs}: {ours:.2} GB/s, memcpy {baseline:.2} GB/s");
+    if let Some(g) = gnu {
+        print!(", GNU dd {g:.2} GB/s, {:.0}% of it", ours / g * 100.0);
+    }
+    println!();
+
+    let floor = gnu.map_or(baseline / divisor, |g| g * GNU_FRACTION);
+    assert!(ours >= floor, "bs={bs}: {ours:.2} GB/s is below the floor of {floor:.2} GB/s");
+}
+
+#[test]
+#[ignore]
+fn tmpfs_bs_512() {
+    // every block costs a read and a write syscall, which dominate at this size
+    bench(512, 32.0);
+}
+
+#[test]
+#[ignore]
+fn tmpfs_bs_4m() {
+    // reading /dev/zero clears the buffer and writing to tmpfs copies it, so two passes over memory
+    bench(4 * 1024 * 1024, 6.0);
+}
@@ -0,0 +1,99 @@
+//! `--verify` reading the output back, against the input when it is a file and against hashes
+//! recorded while copying when it is a pipe.
+//!
+//! Corruption is injected by overwriting part of the output while a `--rate-limit`ed copy is
+//! still writing the rest of it.
+
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const LEN: usize = 3 * 1024 * 1024;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dd-verify-{name}-{}", std::process::id()))
+}
+
+fn data() -> Vec<u8> {
+    (0..LEN).map(|i| (i * 13 + i / 1021) as u8).collect()
+}
+
+/// Copies [data] to a new file with `--verify` and `args`, from a file or else from a pipe,
+/// flipping the byte at `corrupt` once it has been written. Returns the exit code and stderr.
+fn run(name: &str, from_file: bool, args: &[&str], corrupt: Option<u64>) -> (Option<i32>, String) {
+    let (input, output) = (path(&format!("{name}-in")), path(&format!("{name}-out")));
+    let data = data();
+    let _ = std::fs::remove_file(&output);
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"));
+    cmd.args(["--no-cfg", "--verify", "bs=64K"]).arg(format!("of={}", output.display())).args(args).stderr(Stdio::piped());
+    if from_file {
+        std::fs::write(&input, &data).unwrap();
+        cmd.arg(format!("if={}", input.display()));
+    } else {
+        cmd.arg("iflag=fullblock").stdin(Stdio::piped());
+    }
+    let mut child = cmd.spawn().unwrap();
+    let feeder = child.stdin.take().map(|mut stdin| {
+        let data = data.clone();
+        std::thread::spawn(move || stdin.write_all(&data).unwrap())
+    });
+
+    if let Some(at) = corrupt {
+        let start = Instant::now();
+        while std::fs::metadata(&output).map_or(0, |m| m.len()) <= at + 64 * 1024 {
+            assert!(start.elapsed() < Duration::from_secs(5), "output never reached offset {at}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let f = std::fs::OpenOptions::new().write(true).open(&output).unwrap();
+        f.write_all_at(&[!data[at as usize]], at).unwrap();
+    }
+
+    let out = child.wait_with_output().unwrap();
+    if let Some(f) = feeder {
+        f.join().unwrap();
+    }
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+    (out.status.code(), String::from_utf8(out.stderr).unwrap())
+}
+
+#[test]
+fn clean_copy_passes() {
+    for from_file in [true, false] {
+        let (code, err) = run("clean", from_file, &[], None);
+        assert_eq!(code, Some(0), "{err}");
+        assert!(err.contains(&format!("Verified {LEN} bytes")), "{err}");
+    }
+}
+
+#[test]
+fn corrupt_file_copy_fails() {
+    let (code, err) = run("corrupt-file", true, &["--rate-limit", "2M"], Some(1000));
+    assert_eq!(code, Some(0x50), "{err}");
+    // compared with the input, so the exact byte is known
+    assert!(err.contains("first mismatch at offset 1000, 1 of 48 blocks of 65536 bytes differ"), "{err}");
+}
+
+#[test]
+fn corrupt_pipe_copy_fails() {
+    let (code, err) = run("corrupt-pipe", false, &["--rate-limit", "2M"], Some(1000));
+    assert_eq!(code, Some(0x50), "{err}");
+    // compared with hashes of 1 MiB blocks
+    assert!(err.contains("first mismatching block at offset 0, 1 of 3 blocks of 1048576 bytes differ"), "{err}");
+}
+
+#[test]
+fn stdout_can_not_be_verified() {
+    let out = Command::new(env!("CARGO_BIN_EXE_disk-destroyer"))
+        .args(["--no-cfg", "--verify", "if=/dev/zero", "count=1"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert_eq!(out.status.code(), Some(0x51), "{err}");
+    assert!(err.contains("Can not verify"), "{err}");
+}